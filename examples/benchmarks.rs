@@ -0,0 +1,34 @@
+// Runs every benchmark a few times and compares how long they took to solve against what's expected.
+//
+// cargo run --release --example benchmarks --features benchmarks
+
+use neat::{benchmarks::Benchmark, config::Config};
+
+const RUNS: usize = 5;
+
+fn main() {
+    for benchmark in [
+        Benchmark::parity(2),
+        Benchmark::parity(3),
+        Benchmark::sine(),
+        Benchmark::cart_pole(),
+    ] {
+        // Give up once a run takes a lot longer than expected
+        let max_generations = benchmark.expected_generations * 4;
+        let runs = (0..RUNS)
+            .map(|_| benchmark.solve(Config::default(), max_generations))
+            .collect::<Vec<_>>();
+        let solved = runs.iter().flatten().collect::<Vec<_>>();
+        let mean = solved.iter().copied().sum::<usize>() as f32 / solved.len().max(1) as f32;
+
+        println!(
+            "{:10} ({} inputs) | SOLVED: {}/{} | MEAN GENS: {:.1} | EXPECTED: {}",
+            benchmark.name,
+            benchmark.inputs,
+            solved.len(),
+            RUNS,
+            mean,
+            benchmark.expected_generations
+        );
+    }
+}
@@ -0,0 +1,46 @@
+// Times add-edge-heavy mutation, where checking candidate edges for loops is most of the work.
+//
+// cargo run --release --example mutation_bench
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::Instant;
+
+use neat::{config::Config, genome::Genome, trainer::Trainer};
+
+const GENES: usize = 150;
+const MUTATIONS: usize = 3000;
+
+fn main() {
+    let config = Config::builder().mutate_add_node(0.5).build();
+    let trainer = Arc::new(Trainer::new_with_config(8, 4, config));
+
+    // Grow a genome big enough for the loop checks to matter
+    let mut genome = Genome::new(trainer.clone());
+    while genome.genes.len() < GENES {
+        genome = genome.mutate();
+    }
+
+    // Every mutation tries to add an edge, and only that
+    let config = Config::builder()
+        .mutate_weight(0.0)
+        .mutate_add_node(0.0)
+        .mutate_add_edge(1.0)
+        .mutate_add_edge_tries(20)
+        .build();
+    trainer.set_config(config).unwrap();
+
+    let start = Instant::now();
+    for _ in 0..MUTATIONS {
+        black_box(genome.mutate());
+    }
+    let time = start.elapsed();
+
+    println!(
+        "{} mutations of a {} gene genome | TOTAL: {}ms | EACH: {:.1}us",
+        MUTATIONS,
+        genome.genes.len(),
+        time.as_millis(),
+        time.as_secs_f64() * 1e6 / MUTATIONS as f64
+    );
+}
@@ -0,0 +1,39 @@
+// Loads a serialized network and runs it using only things that work on
+// `wasm32-unknown-unknown` (no threads or system clock).
+//
+// cargo build --example wasm_simulate --target wasm32-unknown-unknown --no-default-features --features serde
+
+use std::collections::BTreeMap;
+
+use neat::network::{Activations, Gene, Network, NodeLayout};
+
+/// Loads a network saved with bincode and runs it
+fn simulate(network: &[u8], sensors: &[f32]) -> Vec<f32> {
+    let network = bincode::deserialize::<Network>(network).unwrap();
+    network.simulate(sensors)
+}
+
+fn main() {
+    // Two sensors connected to an output through one hidden node
+    // Normally this would be saved from `Genome::network` after training
+    let gene = |node_in, node_out, weight, innovation| Gene {
+        node_in,
+        node_out,
+        weight,
+        enabled: true,
+        innovation,
+        generation_added: 0,
+    };
+    let network = Network {
+        layout: NodeLayout::new(2, 1),
+        genes: vec![gene(0, 3, 0.5, 0), gene(1, 3, -0.5, 1), gene(3, 2, 1.0, 2)],
+        activations: Activations::default(),
+        node_activations: BTreeMap::new(),
+        value_limit: f32::MAX,
+    };
+    let bytes = bincode::serialize(&network).unwrap();
+
+    for i in [[0.0, 0.0], [0.0, 1.0], [1.0, 0.0], [1.0, 1.0]] {
+        println!("{:?} -> {:?}", i, simulate(&bytes, &i));
+    }
+}
@@ -0,0 +1,166 @@
+// Standard problems for checking that changes don't make training worse
+
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use crate::{
+    config::Config,
+    genome::Genome,
+    misc::{sigmoid, tanh},
+    trainer::Trainer,
+};
+
+/// A problem with a ready made fitness function.
+/// Sensor 0 is always the bias and fitness is always between 0 and 1.
+pub struct Benchmark {
+    pub name: &'static str,
+    /// The number of sensors, including the bias
+    pub inputs: usize,
+    pub outputs: usize,
+    /// Roughly how many generations the default config takes to solve the problem,
+    /// a run taking a lot longer than this is a sign something got worse
+    pub expected_generations: usize,
+    /// The fitness a genome needs to count as solving the problem
+    pub solved_fitness: f32,
+    fitness: Box<dyn Fn(&Genome) -> f32 + Send + Sync>,
+}
+
+impl Benchmark {
+    /// The output (through a sigmoid) has to be 1 if an odd number of the `bits` inputs are 1, otherwise 0.
+    /// With 2 bits this is XOR, which the NEAT paper solves in 32 generations on average.
+    /// Every extra bit doubles the expected generations, which is only a rough guess.
+    pub fn parity(bits: usize) -> Self {
+        let cases = (0..1usize << bits)
+            .map(|x| {
+                let mut inputs = vec![1.0];
+                inputs.extend((0..bits).map(|i| (x >> i & 1) as f32));
+                (inputs, (x.count_ones() % 2) as f32)
+            })
+            .collect::<Vec<_>>();
+
+        Self {
+            name: "parity",
+            inputs: bits + 1,
+            outputs: 1,
+            expected_generations: 32 << bits.saturating_sub(2),
+            solved_fitness: 0.9,
+            fitness: Box::new(move |genome| {
+                let err = cases
+                    .iter()
+                    .map(|(inputs, real)| (real - sigmoid(genome.simulate(inputs)[0])).abs())
+                    .sum::<f32>();
+                1.0 - err / cases.len() as f32
+            }),
+        }
+    }
+
+    /// The output (through a tanh) has to approximate `sin(x)` for `x` in `[-π, π]`,
+    /// which is given as an input scaled to `[-1, 1]`
+    pub fn sine() -> Self {
+        const SAMPLES: usize = 20;
+
+        Self {
+            name: "sine",
+            inputs: 2,
+            outputs: 1,
+            expected_generations: 100,
+            solved_fitness: 0.9,
+            fitness: Box::new(|genome| {
+                let err = (0..SAMPLES)
+                    .map(|i| {
+                        let x = i as f32 / (SAMPLES - 1) as f32 * 2.0 - 1.0;
+                        let got = tanh(genome.simulate(&[1.0, x])[0]);
+                        (got - (x * PI).sin()).abs() / 2.0
+                    })
+                    .sum::<f32>();
+                1.0 - err / SAMPLES as f32
+            }),
+        }
+    }
+
+    /// A simple cart-pole: the inputs are the cart position and velocity and the pole angle and angular velocity,
+    /// the cart is pushed right if the output is positive and left otherwise.
+    /// Fitness is the fraction of steps the pole stays up and the cart stays on the track, averaged over a few starting states.
+    pub fn cart_pole() -> Self {
+        const STEPS: usize = 500;
+        const STARTS: [[f32; 4]; 4] = [
+            [0.0, 0.0, 0.05, 0.0],
+            [0.0, 0.0, -0.05, 0.0],
+            [0.5, 0.0, 0.02, 0.1],
+            [-0.5, 0.0, -0.02, -0.1],
+        ];
+
+        Self {
+            name: "cart_pole",
+            inputs: 5,
+            outputs: 1,
+            expected_generations: 10,
+            solved_fitness: 1.0,
+            fitness: Box::new(|genome| {
+                let survived = STARTS
+                    .iter()
+                    .map(|&start| {
+                        let mut state = start;
+                        for step in 0..STEPS {
+                            let [x, _, theta, _] = state;
+                            if x.abs() > 2.4 || theta.abs() > 0.21 {
+                                return step;
+                            }
+
+                            let out =
+                                genome.simulate(&[1.0, state[0], state[1], state[2], state[3]]);
+                            let force = if out[0] > 0.0 { 10.0 } else { -10.0 };
+                            state = cart_pole_step(state, force);
+                        }
+                        STEPS
+                    })
+                    .sum::<usize>();
+                survived as f32 / (STEPS * STARTS.len()) as f32
+            }),
+        }
+    }
+
+    /// Scores a genome on this problem
+    pub fn fitness(&self, genome: &Genome) -> f32 {
+        (self.fitness)(genome)
+    }
+
+    /// Makes a populated trainer with the right sensors and outputs for this problem
+    pub fn trainer(&self, config: Config) -> Arc<Trainer> {
+        Arc::new(Trainer::new_with_config(self.inputs, self.outputs, config).with_bias(0))
+            .populate()
+    }
+
+    /// Evolves a new population until a genome reaches `solved_fitness`.
+    /// Returns the number of generations it took, or `None` if it wasn't solved within `max_generations`
+    pub fn solve(&self, config: Config, max_generations: usize) -> Option<usize> {
+        let trainer = self.trainer(config);
+        (1..=max_generations).find(|_| trainer.gen(|_, x| self.fitness(x)).1 >= self.solved_fitness)
+    }
+}
+
+// Moves the cart-pole simulation forward by 0.02 seconds
+// state: [cart position, cart velocity, pole angle, pole angular velocity]
+fn cart_pole_step(state: [f32; 4], force: f32) -> [f32; 4] {
+    const GRAVITY: f32 = 9.8;
+    const CART_MASS: f32 = 1.0;
+    const POLE_MASS: f32 = 0.1;
+    const POLE_HALF_LENGTH: f32 = 0.5;
+    const TAU: f32 = 0.02;
+
+    let [x, x_dot, theta, theta_dot] = state;
+    let total_mass = CART_MASS + POLE_MASS;
+    let (sin, cos) = theta.sin_cos();
+
+    let temp = (force + POLE_MASS * POLE_HALF_LENGTH * theta_dot.powi(2) * sin) / total_mass;
+    let theta_acc = (GRAVITY * sin - cos * temp)
+        / (POLE_HALF_LENGTH * (4.0 / 3.0 - POLE_MASS * cos.powi(2) / total_mass));
+    let x_acc = temp - POLE_MASS * POLE_HALF_LENGTH * theta_acc * cos / total_mass;
+
+    [
+        x + TAU * x_dot,
+        x_dot + TAU * x_acc,
+        theta + TAU * theta_dot,
+        theta_dot + TAU * theta_acc,
+    ]
+}
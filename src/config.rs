@@ -1,37 +1,246 @@
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt::Display;
+
+use rand::Rng;
+
+use crate::genome::Genome;
+use crate::misc::fitness_cmp;
+use crate::network::{Activation, Activations, OutputMode};
+
+#[derive(Debug, Clone)]
 pub struct Config {
     // == BASIC ==
     /// The size of the population
     pub population_size: usize,
 
     // == POPULATION  ==
-    /// The chance of a node to have an edge on population init
-    // pub init_edge_chance: f32,
+    /// Which sensors are connected to which outputs on population init
+    pub init_connectivity: InitConnectivity,
+    /// How the weights of new edges are picked, also used when a weight is reset by mutation
+    pub weight_init: WeightInit,
     /// Percent of the popluation to eggstermanate before repopulation
     pub population_kill_percent: f32,
+    /// Kill `population_kill_percent` of each species (always leaving one member) instead of the whole population
+    pub cull_per_species: bool,
+    /// If culling leaves fewer genomes than this, fresh ones are added before repopulating.
+    /// Has to be at least 1, so there is always something to breed from even if every genome was lethal
+    pub min_population: usize,
+    /// Species younger than this many generations are not culled, giving new structure time to improve
+    pub species_grace_period: usize,
+    /// If the fitness given to genomes is divided by the size of their species (explicit fitness sharing).
+    /// This is the fitness culling, species fitness and picking the fitter parent in crossover go off of.
+    /// As every genome in a species is divided by the same count it only changes rankings across species.
+    pub selection_uses_adjusted_fitness: bool,
+    /// How parents are picked from their species in `repopulate`
+    pub selection: SelectionMethod,
+    /// How genomes with the same fitness are ordered when picking the best genome, champions and which genomes are culled
+    pub tie_break: TieBreak,
+    /// What happens to the genomes left after culling, see [`ReplacementStrategy`]
+    pub replacement: ReplacementStrategy,
+    /// The fraction of the population replaced by children each generation, if using `ReplacementStrategy::Generational`.
+    /// The rest is made up of the fittest genomes that survived culling (along with the champions), carried over unchanged. `1` replaces everyone
+    pub generation_gap: f32,
+    /// Species with more genomes than this have their best genome copied into the next generation unchanged
+    pub champion_min_size: usize,
+    /// Species with fewer genomes than this are dissolved after categorizing, with their genomes moved to the nearest species that is big enough.
+    /// If no species is big enough the biggest one is kept. `1` keeps every species
+    pub min_species_size: usize,
+    /// The number of best genomes (by raw fitness) kept from across the whole run, see `Trainer::hall_of_fame`. `0` keeps none
+    pub hall_of_fame_size: usize,
 
     // == COMPATIBILITY COEFFICIENTS ==
     pub excess_comp: f32,
     pub disjoint_comp: f32,
     pub weight_comp: f32,
     pub compatibility_threshold: f32,
+    /// Set `compatibility_threshold` to the median distance between genomes of the initial population in [`Trainer::populate`](crate::trainer::Trainer::populate),
+    /// so speciation is scaled to the problem. Up to `diversity_sample_size` genomes are sampled
+    pub auto_init_threshold: bool,
+    /// Put genomes into species in id order with the lowest id member representing each species, instead of randomly.
+    /// This makes speciation reproducible.
+    pub deterministic_speciation: bool,
+    /// The number of genomes `Trainer::diversity` compares with each other
+    pub diversity_sample_size: usize,
+    /// The most fitness futures `Trainer::gen_async` runs at once
+    pub async_max_in_flight: usize,
 
     // == MUTATION CHANCES ==
     /// The chance to mutate an edges weight
     pub mutate_weight: f32,
     /// The chance to reset an edges weight (if being mutated)
     pub mutate_weight_reset: f32,
+    /// The max amount an edges weight can be nudged by when perturbed (not reset)
+    pub weight_perturb_power: f32,
     /// The chance to add a node to genome
     pub mutate_add_node: f32,
+    /// How the edge to split is picked when adding a node
+    pub add_node_selection: AddNodeSelection,
+    /// Genomes with fewer genes than this are biased toward splitting older edges, if using `AddNodeSelection::OlderBiased`
+    pub add_node_young_bias_threshold: usize,
     /// The chance to add an edge to genome
     pub mutate_add_edge: f32,
     /// The number of attempts to make on creating a new edge
     pub mutate_add_edge_tries: usize,
+    /// When to check that changes to a genome don't make a loop, see [`RecursionCheck`]
+    pub recursion_check: RecursionCheck,
     /// The chance to disable an edge
     pub mutate_disable_edge: f32,
+    /// The chance to enable a disabled edge
+    pub mutate_enable_edge: f32,
+    /// The chance to change the activation of a random hidden node
+    pub mutate_activation: f32,
+    /// Disable enabled edges whose weight is within `prune_weight_epsilon` of 0 after mutating weights, they carry almost no signal
+    pub mutate_prune_zero_weights: bool,
+    /// Edges with an absolute weight below this are disabled, if using `mutate_prune_zero_weights`
+    pub prune_weight_epsilon: f32,
+    /// Scale each species chance to add nodes and edges by how long it has been stagnant, see `Specie::mutation_scale`
+    pub adaptive_mutation: bool,
+    /// How much the add node / edge chances go up for every generation a species is stagnant, if using `adaptive_mutation`
+    pub adaptive_mutation_rate: f32,
+    /// The most the add node / edge chances can be multiplied by, if using `adaptive_mutation`
+    pub adaptive_mutation_max: f32,
 
     // == CROSSOVER CHANCES ==
     pub crossover_keep_disabled: f32,
+    /// The chance for a matching gene to get a weight randomly interpolated between both parents' weights,
+    /// instead of the weight of the parent it was picked from
+    pub crossover_blend: f32,
+    /// The number of attempts to make on creating a non recursive child from two parents
     pub crossover_trys: usize,
+
+    // == LIMITS ==
+    /// The most nodes (sensors and outputs included) a genome can grow to through mutation
+    pub max_nodes: Option<usize>,
+    /// The most edges (disabled ones included) a genome can grow to through mutation
+    pub max_edges: Option<usize>,
+
+    // == ACTIVATIONS ==
+    /// The activation used by hidden nodes
+    pub hidden_activation: Activation,
+    /// The activation used by output nodes
+    /// Linear leaves outputs unsquashed, which is what you want for regression
+    pub output_activation: Activation,
+    /// How the outputs are put together after their activation, like a softmax for classification
+    pub output_mode: OutputMode,
+    /// Node values are clamped to plus or minus this when simulating so infinities stay finite, NaNs become 0
+    pub value_limit: f32,
+
+    // == NOVELTY SEARCH ==
+    /// The number of nearest behaviors a genomes novelty is averaged over
+    pub novelty_k: usize,
+    /// The novelty a behavior needs to be added to the archive
+    pub novelty_archive_threshold: f32,
+
+    // == STATS ==
+    /// Keep a [`crate::trainer::GenerationStats`] and the species sizes for every generation, see `Trainer::history_csv` and `Trainer::species_history`
+    pub record_history: bool,
+}
+
+/// How new genomes connect their sensors to their outputs
+#[derive(Debug, Clone, Copy)]
+pub enum InitConnectivity {
+    /// Every sensor is connected to every output
+    Full,
+    /// No edges at all, everything has to be added by mutation
+    None,
+    /// Each sensor is connected to each output with this chance.
+    /// Genomes always start with at least one edge.
+    Sparse(f32),
+}
+
+/// How the add-node mutation picks which edge to split
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddNodeSelection {
+    /// Every enabled edge is as likely
+    Uniform,
+    /// While a genome has fewer than `add_node_young_bias_threshold` genes, older edges are more likely
+    OlderBiased,
+}
+
+/// When genomes are checked for loops.
+/// Only feedforward simulation exists, there is no recurrent mode.
+/// Nodes in a loop and every node downstream of one are never worked out,
+/// so an output fed by a loop always gives the output activation of 0, even if it also has edges straight from sensors.
+/// Skipping checks can quietly turn outputs constant.
+/// The checks are cheap for small genomes, only turn them down if profiling shows they matter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecursionCheck {
+    /// New edges, re-enabled edges and crossover children are all checked
+    Always,
+    /// Only new edges are checked, re-enabling an edge or crossing over two genomes can still make a loop
+    OnAddEdgeOnly,
+    /// Nothing is checked, only for when constant outputs from loops don't matter to the fitness function
+    Never,
+}
+
+/// What happens to the genomes left after culling.
+/// Either way culling decides which genomes can be parents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplacementStrategy {
+    /// The whole population is replaced by children every generation,
+    /// only species champions (see `champion_min_size`) are carried over unchanged
+    Generational,
+    /// Genomes that survive culling are kept unchanged and only the culled ones are replaced by children.
+    /// Champions are among the survivors, so they aren't added again
+    SteadyState,
+}
+
+/// How genomes with the same fitness are ordered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TieBreak {
+    /// Ties are left in whatever order the population is in
+    None,
+    /// The genome with the smaller [`Genome::parameter_count`] is better, then the one with the smaller id (the older one).
+    /// Prefers simpler networks and keeps results reproducible
+    Simplest,
+}
+
+impl TieBreak {
+    /// Compares two genomes along with their fitness, the better one is `Greater`
+    pub fn compare(&self, a: (&Genome, f32), b: (&Genome, f32)) -> Ordering {
+        let ordering = fitness_cmp(a.1, b.1);
+        match self {
+            TieBreak::None => ordering,
+            TieBreak::Simplest => ordering
+                .then_with(|| b.0.parameter_count().cmp(&a.0.parameter_count()))
+                .then_with(|| b.0.id.cmp(&a.0.id)),
+        }
+    }
+}
+
+/// How parents are picked from a species when repopulating
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionMethod {
+    /// Every genome in the species is as likely, no matter its fitness
+    Uniform,
+    /// Stochastic universal sampling, genomes are picked in proportion to their fitness
+    /// with evenly spaced pointers, so each genome gets close to its expected number of children
+    Sus,
+}
+
+/// How the weights of new edges are picked
+#[derive(Debug, Clone, Copy)]
+pub enum WeightInit {
+    /// Uniformly from `-x..=x`
+    Uniform(f32),
+    /// From a normal distribution
+    Gaussian { mean: f32, std: f32 },
+}
+
+impl WeightInit {
+    pub fn sample(&self, rng: &mut impl Rng) -> f32 {
+        match *self {
+            WeightInit::Uniform(x) => rng.gen_range(-x..=x),
+            // Box-Muller transform
+            WeightInit::Gaussian { mean, std } => {
+                let u1 = 1.0 - rng.gen::<f32>();
+                let u2 = rng.gen::<f32>();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos();
+                mean + z * std
+            }
+        }
+    }
 }
 
 // Default values stolen from the NEAT paper
@@ -39,19 +248,344 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             population_size: 150,
+            init_connectivity: InitConnectivity::Full,
+            weight_init: WeightInit::Uniform(1.0),
             population_kill_percent: 0.9,
+            cull_per_species: true,
+            min_population: 2,
+            species_grace_period: 0,
+            selection_uses_adjusted_fitness: true,
+            selection: SelectionMethod::Uniform,
+            tie_break: TieBreak::Simplest,
+            replacement: ReplacementStrategy::Generational,
+            generation_gap: 1.0,
+            champion_min_size: 5,
+            min_species_size: 1,
+            hall_of_fame_size: 10,
             excess_comp: 1.0,
             disjoint_comp: 0.5,
             weight_comp: 0.4,
             compatibility_threshold: 15.0,
+            auto_init_threshold: false,
+            deterministic_speciation: false,
+            diversity_sample_size: 50,
+            async_max_in_flight: 16,
             mutate_weight: 0.9,
             mutate_weight_reset: 0.2,
+            weight_perturb_power: 0.5,
             mutate_add_node: 0.03,
+            add_node_selection: AddNodeSelection::Uniform,
+            add_node_young_bias_threshold: 15,
             mutate_add_edge: 0.5,
             mutate_add_edge_tries: 20,
+            recursion_check: RecursionCheck::Always,
             mutate_disable_edge: 0.0,
+            mutate_enable_edge: 0.0,
+            mutate_activation: 0.0,
+            mutate_prune_zero_weights: false,
+            prune_weight_epsilon: 1e-3,
+            adaptive_mutation: false,
+            adaptive_mutation_rate: 0.1,
+            adaptive_mutation_max: 3.0,
             crossover_keep_disabled: 0.4,
+            crossover_blend: 0.0,
             crossover_trys: 5,
+            max_nodes: None,
+            max_edges: None,
+            hidden_activation: Activation::Sigmoid,
+            output_activation: Activation::Linear,
+            output_mode: OutputMode::Independent,
+            value_limit: f32::MAX,
+            novelty_k: 15,
+            novelty_archive_threshold: 1.0,
+            record_history: false,
+        }
+    }
+}
+
+/// Builds a [`Config`] by changing values from the defaults
+#[derive(Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+macro_rules! setters {
+    ($($name:ident: $type:ty),* $(,)?) => {
+        $(
+            pub fn $name(mut self, $name: $type) -> Self {
+                self.config.$name = $name;
+                self
+            }
+        )*
+    };
+}
+
+impl Config {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// Checks that the config makes sense, naming the first field that doesn't
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        // Crossover needs two genomes to breed from
+        if self.population_size < 2 {
+            return Err(ConfigError::PopulationSize);
+        }
+
+        if self.min_population < 1 {
+            return Err(ConfigError::MinPopulation);
+        }
+
+        let probabilities = [
+            ("population_kill_percent", self.population_kill_percent),
+            ("mutate_weight", self.mutate_weight),
+            ("mutate_weight_reset", self.mutate_weight_reset),
+            ("mutate_add_node", self.mutate_add_node),
+            ("mutate_add_edge", self.mutate_add_edge),
+            ("mutate_disable_edge", self.mutate_disable_edge),
+            ("mutate_enable_edge", self.mutate_enable_edge),
+            ("mutate_activation", self.mutate_activation),
+            ("crossover_keep_disabled", self.crossover_keep_disabled),
+            ("crossover_blend", self.crossover_blend),
+            ("generation_gap", self.generation_gap),
+        ];
+        for (name, value) in probabilities {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(ConfigError::Probability(name));
+            }
+        }
+
+        if let InitConnectivity::Sparse(chance) = self.init_connectivity {
+            if !(0.0..=1.0).contains(&chance) {
+                return Err(ConfigError::Probability("init_connectivity"));
+            }
+        }
+
+        let numbers = [
+            ("excess_comp", self.excess_comp),
+            ("disjoint_comp", self.disjoint_comp),
+            ("weight_comp", self.weight_comp),
+            ("compatibility_threshold", self.compatibility_threshold),
+            ("weight_perturb_power", self.weight_perturb_power),
+            ("value_limit", self.value_limit),
+            ("novelty_archive_threshold", self.novelty_archive_threshold),
+            ("prune_weight_epsilon", self.prune_weight_epsilon),
+            ("adaptive_mutation_rate", self.adaptive_mutation_rate),
+            ("adaptive_mutation_max", self.adaptive_mutation_max),
+        ];
+        for (name, value) in numbers {
+            if !value.is_finite() {
+                return Err(ConfigError::NotFinite(name));
+            }
+        }
+
+        // Sampling panics on an empty range, so the spread of new weights can't be negative
+        let (spread, mean) = match self.weight_init {
+            WeightInit::Uniform(x) => (x, 0.0),
+            WeightInit::Gaussian { mean, std } => (std, mean),
+        };
+        if !spread.is_finite() || !mean.is_finite() {
+            return Err(ConfigError::NotFinite("weight_init"));
+        }
+        if spread < 0.0 {
+            return Err(ConfigError::Negative("weight_init"));
+        }
+
+        if self.value_limit <= 0.0 {
+            return Err(ConfigError::NotPositive("value_limit"));
+        }
+
+        Ok(())
+    }
+
+    pub fn activations(&self) -> Activations {
+        Activations {
+            hidden: self.hidden_activation,
+            output: self.output_activation,
+            output_mode: self.output_mode,
+        }
+    }
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    setters! {
+        population_size: usize,
+        init_connectivity: InitConnectivity,
+        weight_init: WeightInit,
+        population_kill_percent: f32,
+        cull_per_species: bool,
+        min_population: usize,
+        species_grace_period: usize,
+        selection_uses_adjusted_fitness: bool,
+        selection: SelectionMethod,
+        tie_break: TieBreak,
+        replacement: ReplacementStrategy,
+        generation_gap: f32,
+        champion_min_size: usize,
+        min_species_size: usize,
+        hall_of_fame_size: usize,
+        excess_comp: f32,
+        disjoint_comp: f32,
+        weight_comp: f32,
+        compatibility_threshold: f32,
+        auto_init_threshold: bool,
+        deterministic_speciation: bool,
+        diversity_sample_size: usize,
+        async_max_in_flight: usize,
+        mutate_weight: f32,
+        mutate_weight_reset: f32,
+        weight_perturb_power: f32,
+        mutate_add_node: f32,
+        add_node_selection: AddNodeSelection,
+        add_node_young_bias_threshold: usize,
+        mutate_add_edge: f32,
+        mutate_add_edge_tries: usize,
+        recursion_check: RecursionCheck,
+        mutate_disable_edge: f32,
+        mutate_enable_edge: f32,
+        mutate_activation: f32,
+        mutate_prune_zero_weights: bool,
+        prune_weight_epsilon: f32,
+        adaptive_mutation: bool,
+        adaptive_mutation_rate: f32,
+        adaptive_mutation_max: f32,
+        crossover_keep_disabled: f32,
+        crossover_blend: f32,
+        crossover_trys: usize,
+        max_nodes: Option<usize>,
+        max_edges: Option<usize>,
+        hidden_activation: Activation,
+        output_activation: Activation,
+        output_mode: OutputMode,
+        value_limit: f32,
+        novelty_k: usize,
+        novelty_archive_threshold: f32,
+        record_history: bool,
+    }
+
+    pub fn build(self) -> Config {
+        self.config
+    }
+}
+
+/// Why a [`Config`] is invalid, see [`Config::validate`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// `population_size` is less than 2
+    PopulationSize,
+    /// `min_population` is 0
+    MinPopulation,
+    /// This field is a chance, but isn't between 0 and 1
+    Probability(&'static str),
+    /// This field is infinite or NaN
+    NotFinite(&'static str),
+    /// This field is less than 0
+    Negative(&'static str),
+    /// This field is 0 or less
+    NotPositive(&'static str),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::PopulationSize => f.write_str("population_size has to be at least 2"),
+            ConfigError::MinPopulation => f.write_str("min_population has to be at least 1"),
+            ConfigError::Probability(name) => write!(f, "{name} has to be between 0 and 1"),
+            ConfigError::NotFinite(name) => write!(f, "{name} has to be a finite number"),
+            ConfigError::Negative(name) => write!(f, "{name} can't be negative"),
+            ConfigError::NotPositive(name) => write!(f, "{name} has to be more than 0"),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ConfigError, InitConnectivity, WeightInit};
+
+    #[test]
+    fn min_population_zero_is_rejected() {
+        let config = Config::builder().min_population(0).build();
+        assert_eq!(config.validate(), Err(ConfigError::MinPopulation));
+        assert_eq!(
+            Config::builder().min_population(1).build().validate(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn default_is_valid() {
+        assert_eq!(Config::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn invalid_fields_are_named() {
+        let cases = [
+            (
+                Config::builder().population_size(1).build(),
+                ConfigError::PopulationSize,
+            ),
+            (
+                Config::builder().mutate_weight(2.0).build(),
+                ConfigError::Probability("mutate_weight"),
+            ),
+            (
+                Config::builder().crossover_keep_disabled(-0.1).build(),
+                ConfigError::Probability("crossover_keep_disabled"),
+            ),
+            (
+                Config::builder()
+                    .init_connectivity(InitConnectivity::Sparse(f32::NAN))
+                    .build(),
+                ConfigError::Probability("init_connectivity"),
+            ),
+            (
+                Config::builder()
+                    .init_connectivity(InitConnectivity::Sparse(1.5))
+                    .build(),
+                ConfigError::Probability("init_connectivity"),
+            ),
+            (
+                Config::builder().excess_comp(f32::INFINITY).build(),
+                ConfigError::NotFinite("excess_comp"),
+            ),
+            (
+                Config::builder()
+                    .weight_init(WeightInit::Uniform(-1.0))
+                    .build(),
+                ConfigError::Negative("weight_init"),
+            ),
+            (
+                Config::builder()
+                    .weight_init(WeightInit::Gaussian {
+                        mean: f32::NAN,
+                        std: 1.0,
+                    })
+                    .build(),
+                ConfigError::NotFinite("weight_init"),
+            ),
+            (
+                Config::builder()
+                    .weight_init(WeightInit::Gaussian {
+                        mean: 0.0,
+                        std: -1.0,
+                    })
+                    .build(),
+                ConfigError::Negative("weight_init"),
+            ),
+            (
+                Config::builder().value_limit(0.0).build(),
+                ConfigError::NotPositive("value_limit"),
+            ),
+        ];
+
+        for (config, error) in cases {
+            assert_eq!(config.validate(), Err(error));
         }
     }
 }
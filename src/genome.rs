@@ -1,20 +1,22 @@
-use std::cell::RefCell;
 use std::cmp::Ordering;
-use std::fmt::Debug;
-use std::rc::Rc;
-use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt::{Debug, Display};
+use std::sync::{Arc, OnceLock};
 
 use ahash::{HashMap, HashMapExt, HashSet, HashSetExt};
-use rand::distributions::WeightedIndex;
-use rand::prelude::Distribution;
 use rand::{
+    distributions::{Distribution, WeightedIndex},
     seq::{IteratorRandom, SliceRandom},
     thread_rng, Rng,
 };
 
+pub use crate::network::{Gene, NodeType};
 use crate::{
+    config::{AddNodeSelection, Config, InitConnectivity, RecursionCheck},
     innovation::EdgeCount,
-    misc::{sigmoid, SignString},
+    misc::fitness_cmp,
+    network::{self, Activation, Network, NodeLayout, SimulateCtx, SimulateStats},
     trainer::Trainer,
 };
 
@@ -22,47 +24,62 @@ use crate::{
 pub struct Genome {
     pub(crate) trainer: Arc<Trainer>,
 
+    /// Always sorted by innovation number, keep it that way if adding genes by hand.
+    /// If changing which genes are enabled (or adding / removing genes) by hand,
+    /// call [`Genome::invalidate_topology`] afterwards
     pub genes: Vec<Gene>,
-    node_id: usize,
+    /// Activations of hidden nodes that have been changed by mutation,
+    /// every other hidden node uses `hidden_activation`
+    pub node_activations: BTreeMap<usize, Activation>,
+    /// The node evaluation order, worked out on the first `simulate` after the structure changes
+    topology: OnceLock<Vec<usize>>,
 
     pub id: usize,
     pub species: Option<usize>,
+    /// The fitness from the last generation,
+    /// divided by the size of its species if `selection_uses_adjusted_fitness` is set.
+    /// This is set by the generation loop and is what culling, species fitness and picking parents go off of.
+    /// `None` until the genome has been evaluated, or if the fitness function marked it as lethal.
     pub fitness: Option<f32>,
 }
 
-#[derive(Copy, Clone, Debug)]
-pub struct Gene {
-    pub node_in: usize,
-    pub node_out: usize,
-    pub weight: f32,
-    pub enabled: bool,
-    pub innovation: EdgeCount,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum NodeType {
-    Sensor,
-    Output,
-    Hidden,
-}
-
-#[derive(Clone)]
-struct NodeTester {
-    pub nodes: RefCell<HashMap<usize, Option<f32>>>,
-    pub genes: Genome,
-}
-
 impl Genome {
     pub fn new(trainer: Arc<Trainer>) -> Self {
         let mut genes = Vec::new();
+        let mut rng = thread_rng();
 
-        for i in 0..trainer.inputs {
-            for o in 0..trainer.outputs {
+        let chance = match trainer.config().init_connectivity {
+            InitConnectivity::Full => 1.0,
+            InitConnectivity::None => 0.0,
+            InitConnectivity::Sparse(chance) => chance,
+        };
+
+        for i in trainer.layout.sensor_range() {
+            for o in trainer.layout.output_range() {
                 // Make new gene
-                genes.push(Gene::random(trainer.clone(), i, trainer.inputs + o));
+                if rng.gen_bool(chance.clamp(0.0, 1.0).into()) {
+                    genes.push(Gene::random(trainer.clone(), i, o));
+                }
             }
         }
 
+        // Sparse genomes always get at least one edge
+        if genes.is_empty()
+            && matches!(
+                trainer.config().init_connectivity,
+                InitConnectivity::Sparse(_)
+            )
+        {
+            let i = trainer.layout.sensor_range().choose(&mut rng);
+            let o = trainer.layout.output_range().choose(&mut rng);
+            if let (Some(i), Some(o)) = (i, o) {
+                genes.push(Gene::random(trainer.clone(), i, o));
+            }
+        }
+
+        // Innovations are handed out in the order genomes first make an edge, not in loop order
+        genes.sort_by_key(|x| x.innovation);
+
         // let mango = |a, b| Gene {
         //     node_in: a,
         //     node_out: b,
@@ -84,58 +101,197 @@ impl Genome {
             species: None,
             fitness: None,
             genes,
-            node_id: trainer.inputs + trainer.outputs,
+            node_activations: BTreeMap::new(),
+            topology: OnceLock::new(),
             trainer,
         }
     }
 
+    /// Makes a genome out of existing genes, like ones loaded from a file or written by hand.
+    /// Fails if a gene goes into a sensor, out of an output or connects a node to itself,
+    /// if two genes have the same innovation or if the enabled genes make a loop.
+    /// New innovations and hidden nodes from `trainer` will be above every gene's, but the genes only line up with
+    /// other genomes in crossover and [`Genome::distance`] if their innovations and nodes came from the same trainer.
+    pub fn from_genes(trainer: Arc<Trainer>, mut genes: Vec<Gene>) -> Result<Self, GenomeError> {
+        let layout = trainer.layout;
+        check_layout(&genes, layout)?;
+
+        genes.sort_by_key(|x| x.innovation);
+        if genes.windows(2).any(|x| x[0].innovation == x[1].innovation) {
+            return Err(GenomeError::DuplicateInnovation);
+        }
+
+        if network::is_recursive(&genes, layout) {
+            return Err(GenomeError::Recursive);
+        }
+
+        // Hidden node ids are shared by every genome, so new splits mustn't reuse these ones
+        let nodes = genes
+            .iter()
+            .map(|x| x.node_in.max(x.node_out) + 1)
+            .fold(layout.hidden_start(), usize::max);
+        trainer
+            .innovator
+            .reserve_nodes(nodes - layout.hidden_start());
+        if let Some(last) = genes.last() {
+            trainer.innovator.reserve_edges(last.innovation + 1);
+        }
+        for i in &genes {
+            trainer
+                .innovator
+                .register_edge((i.node_in, i.node_out), i.innovation);
+        }
+
+        Ok(Self {
+            id: trainer.innovator.new_genome(),
+            species: None,
+            fitness: None,
+            genes,
+            node_activations: BTreeMap::new(),
+            topology: OnceLock::new(),
+            trainer,
+        })
+    }
+
     pub fn classify_node(&self, id: usize) -> NodeType {
-        if id < self.trainer.inputs {
-            return NodeType::Sensor;
+        self.trainer.layout.classify(id)
+    }
+
+    /// Gets the number of nodes in the genome (sensors, outputs and hidden)
+    pub fn node_count(&self) -> usize {
+        let mut nodes = HashSet::new();
+        nodes.extend(0..self.trainer.layout.hidden_start());
+        for i in &self.genes {
+            nodes.insert(i.node_in);
+            nodes.insert(i.node_out);
         }
 
-        if id - self.trainer.inputs < self.trainer.outputs {
-            return NodeType::Output;
+        nodes.len()
+    }
+
+    /// Gets the number of enabled edges in the genome
+    pub fn enabled_edge_count(&self) -> usize {
+        self.genes.iter().filter(|x| x.enabled).count()
+    }
+
+    /// Gets the number of trainable parameters, for comparing against fixed topology networks.
+    /// Nodes don't have bias terms of their own (the bias is a sensor), so this is the number of enabled edge weights.
+    pub fn parameter_count(&self) -> usize {
+        self.enabled_edge_count()
+    }
+
+    /// Gets the min, max, mean and standard deviation of the enabled edge weights
+    pub fn weight_stats(&self) -> WeightStats {
+        WeightStats::new(self.genes.iter().filter(|x| x.enabled).map(|x| x.weight))
+    }
+
+    /// Same as [`Genome::weight_stats`] but including disabled edges
+    pub fn weight_stats_all(&self) -> WeightStats {
+        WeightStats::new(self.genes.iter().map(|x| x.weight))
+    }
+
+    /// Gets the number of edges in the longest enabled path from any sensor to any output
+    pub fn depth(&self) -> usize {
+        let mut depths = HashMap::new();
+
+        // In topological order every node going into this one already has its depth.
+        // Nodes no sensor reaches are never given a depth.
+        for &i in self.topology() {
+            if self.classify_node(i) == NodeType::Sensor {
+                depths.insert(i, 0);
+                continue;
+            }
+
+            if let Some(depth) = self
+                .genes
+                .iter()
+                .filter(|x| x.enabled && x.node_out == i)
+                .filter_map(|x| depths.get(&x.node_in))
+                .max()
+            {
+                depths.insert(i, depth + 1);
+            }
         }
 
-        NodeType::Hidden
+        self.trainer
+            .layout
+            .output_range()
+            .filter_map(|x| depths.get(&x))
+            .max()
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Writes the genome as a small JSON format for running it outside of Rust,
+    /// see [`Network::to_inference_json`] for the schema
+    pub fn to_inference_json(&self) -> String {
+        self.network().to_inference_json()
+    }
+
+    /// Gets every node on some enabled path from a sensor to an output.
+    /// Only these nodes are worked out when simulating.
+    pub fn reachable_nodes(&self) -> HashSet<usize> {
+        network::reachable_nodes(&self.genes, self.trainer.layout)
+            .into_iter()
+            .collect()
     }
 
+    /// Checks if both genomes have the same enabled edges, ignoring weights and innovations.
+    /// Unlike [`Genome::distance`] this only looks at what the networks are connected like,
+    /// so it can find duplicate structure that was made independently. Hidden nodes are compared by id.
+    pub fn same_topology(&self, other: &Self) -> bool {
+        let edges = |genome: &Self| {
+            genome
+                .genes
+                .iter()
+                .filter(|x| x.enabled)
+                .map(|x| (x.node_in, x.node_out))
+                .collect::<BTreeSet<_>>()
+        };
+
+        edges(self) == edges(other)
+    }
+
+    /// Gets the compatibility distance to another genome, with the coefficients from the trainer's config
+    pub fn distance(&self, other: &Self) -> f32 {
+        self.distance_with(other, &self.trainer.config())
+    }
+
+    /// Same as [`Genome::distance`] but with the coefficients from `config`,
+    /// so a config snapshot can be reused when working out lots of distances
     // δ = (c1 * E / N) + (c2 * D / N) + c3 * W
     // E: Excess genes
     // D: Disjoint genes
     // W: Weight difference between averaged
     // N: Genes in the larger genome (normalized)
-    pub fn distance(&self, other: &Self) -> f32 {
-        // Gets all the innovations numbers of the 'other' genome
-        let other_innovations = other.genes.iter().map(|x| x.innovation).collect::<Vec<_>>();
-        let self_innovations = self.genes.iter().map(|x| x.innovation).collect::<Vec<_>>();
-        let matching_innovations = self
-            .genes
-            .iter()
-            .map(|x| x.innovation)
-            .filter(|x| other_innovations.contains(x))
-            .collect::<Vec<_>>();
-
-        // Get the count of excess genes
-        let self_max_innovation = self.genes.iter().map(|x| x.innovation).sum::<usize>();
-        let other_max_innovation = other_innovations.iter().sum::<usize>();
-        let min_max_innovation = self_max_innovation.min(other_max_innovation);
-        let e = if self_max_innovation > other_max_innovation {
-            &self.genes
-        } else {
-            &other.genes
-        }
-        .iter()
-        .filter(|x| x.innovation > min_max_innovation)
-        .count();
-
-        // Get the count of disjoint genes
-        let mut disjoint_innovations = other_innovations;
-        disjoint_innovations.extend(self_innovations);
-        disjoint_innovations
-            .retain(|x| *x < min_max_innovation && !matching_innovations.contains(x));
-        let d = disjoint_innovations.len();
+    // Every term is the same either way around, so a.distance(b) == b.distance(a) exactly.
+    // Speciation relies on this.
+    pub fn distance_with(&self, other: &Self, config: &Config) -> f32 {
+        // Walk both sorted gene lists at once, so nothing has to be collected or looked up.
+        // Nonmatching genes found during the walk are disjoint,
+        // whatever is left of the longer list once the other runs out is excess.
+        let (a, b) = (&self.genes, &other.genes);
+        let (mut i, mut j) = (0, 0);
+        let (mut d, mut matching, mut weight_diff) = (0, 0, 0.0);
+        while i < a.len() && j < b.len() {
+            match a[i].innovation.cmp(&b[j].innovation) {
+                Ordering::Equal => {
+                    matching += 1;
+                    weight_diff += (a[i].weight - b[j].weight).abs();
+                    i += 1;
+                    j += 1;
+                }
+                Ordering::Less => {
+                    d += 1;
+                    i += 1;
+                }
+                Ordering::Greater => {
+                    d += 1;
+                    j += 1;
+                }
+            }
+        }
+        let e = (a.len() - i) + (b.len() - j);
 
         // Get the larger gene count (normalized)
         let mut n = self.genes.len().max(other.genes.len());
@@ -144,178 +300,358 @@ impl Genome {
         }
 
         // Get average weight difference
-        let self_matching_weight = self
-            .genes
-            .iter()
-            .filter(|x| matching_innovations.contains(&x.innovation))
-            .map(|x| x.weight);
-        let other_matching_weight = other
-            .genes
-            .iter()
-            .filter(|x| matching_innovations.contains(&x.innovation))
-            .map(|x| x.weight);
-        let mut w = self_matching_weight
-            .zip(other_matching_weight)
-            .map(|(a, b)| (a - b).abs())
-            .sum::<f32>()
-            / matching_innovations.len() as f32;
+        let mut w = weight_diff / matching as f32;
         // further research needed
         if w.is_nan() {
             w = 0.0;
         }
 
         // Distance equation
-        let c1 = self.trainer.config.excess_comp;
-        let c2 = self.trainer.config.disjoint_comp;
-        let c3 = self.trainer.config.weight_comp;
+        let (c1, c2, c3) = (config.excess_comp, config.disjoint_comp, config.weight_comp);
         let n = n as f32;
 
-        (c1 * e as f32 / n) + (c2 * d as f32 / n) + c3 * w as f32
+        (c1 * e as f32 / n) + (c2 * d as f32 / n) + c3 * w
     }
 
     /// Use https://mermaid.live to render debug output
+    #[deprecated = "renamed to `to_mermaid`"]
     pub fn debug(&self) -> String {
-        let mut out = Vec::new();
+        self.to_mermaid()
+    }
 
-        for i in self.genes.iter().filter(|x| x.enabled) {
-            let node_in = self.classify_node(i.node_in);
-            let node_out = self.classify_node(i.node_out);
+    /// Draws the enabled edges of the genome as a Mermaid flowchart, which can be rendered with https://mermaid.live.
+    /// Sensors and outputs are grouped into their own subgraphs, the bias node is drawn as a hexagon
+    /// and edges are labeled with their weights.
+    pub fn to_mermaid(&self) -> String {
+        let layout = self.trainer.layout;
+        let mut out = vec!["flowchart LR".to_owned()];
+
+        out.push("    subgraph Sensors".to_owned());
+        for i in layout.sensor_range() {
+            out.push(if layout.is_bias(i) {
+                format!(r#"        {i}{{{{"Bias {i}"}}}}"#)
+            } else if layout.is_constant(i) {
+                format!(r#"        {i}(["Constant {i}"])"#)
+            } else {
+                format!(r#"        {i}("Sensor {i}")"#)
+            });
+        }
+        out.push("    end".to_owned());
 
+        out.push("    subgraph Outputs".to_owned());
+        for i in layout.output_range() {
+            out.push(format!(r#"        {i}["Output {i}"]"#));
+        }
+        out.push("    end".to_owned());
+
+        let hidden = self
+            .genes
+            .iter()
+            .filter(|x| x.enabled)
+            .flat_map(|x| [x.node_in, x.node_out])
+            .filter(|x| self.classify_node(*x) == NodeType::Hidden)
+            .collect::<BTreeSet<_>>();
+        for i in hidden {
+            out.push(format!(r#"    {i}(("Hidden {i}"))"#));
+        }
+
+        for i in self.genes.iter().filter(|x| x.enabled) {
             out.push(format!(
-                r#"{}("{:?} {}") -{t} {} {t}-> {}["{:?}"]"#,
-                i.node_in,
-                node_in,
-                i.node_in,
-                i.weight.sign_str(),
-                i.node_out,
-                node_out,
-                t = if i.enabled { "-" } else { "." }
+                "    {} -->|{:+.3}| {}",
+                i.node_in, i.weight, i.node_out
             ));
         }
 
         out.join("\n")
     }
 
-    // Checks if a edge from a -> b would cause a loop in the neural network
-    fn would_be_recursive(&self, a: usize, b: usize) -> bool {
-        let mut new = self.clone();
-        new.genes.push(Gene {
+    pub fn is_recursive(&self) -> bool {
+        network::is_recursive(&self.genes, self.trainer.layout)
+    }
+
+    /// Adds an enabled edge from `a` to `b`.
+    /// Fails if the nodes are already connected or the edge would go the wrong way (into a sensor,
+    /// out of an output or make a loop).
+    pub fn add_connection(&mut self, a: usize, b: usize, weight: f32) -> Result<(), MutateError> {
+        // Make sure not pointing to the same node twice, going in order of sensor => (hidden) => output
+        // not the other way around and the connection would not make a recursive connection
+        if a == b {
+            return Err(MutateError::SameNode);
+        }
+
+        if self.genes.iter().any(|x| x.connects(a, b)) {
+            return Err(MutateError::AlreadyConnected);
+        }
+
+        if self.classify_node(a) == NodeType::Output {
+            return Err(MutateError::FromOutput);
+        }
+
+        if self.classify_node(b) == NodeType::Sensor {
+            return Err(MutateError::IntoSensor);
+        }
+
+        if self.trainer.config().recursion_check != RecursionCheck::Never
+            && network::would_loop(&self.genes, a, b)
+        {
+            return Err(MutateError::Recursive);
+        }
+
+        self.insert_gene(Gene {
             node_in: a,
             node_out: b,
-            weight: 0.0,
+            weight,
             enabled: true,
-            innovation: 0,
+            innovation: self.trainer.innovator.new_edge((a, b)),
+            generation_added: self.trainer.generation(),
         });
-        new.is_recursive()
+        self.invalidate_topology();
+        Ok(())
     }
 
-    pub fn is_recursive(&self) -> bool {
-        for i in 0..self.trainer.inputs {
-            let mut seen_nodes = HashSet::new();
-            seen_nodes.insert(i);
+    /// Splits the enabled edge with this innovation by disabling it and adding a new hidden node in its place.
+    /// The edge into the new node gets a weight of 1 and the edge out of it keeps the old weight,
+    /// so the network behaves about the same as before.
+    pub fn split_edge(&mut self, innovation: EdgeCount) -> Result<(), MutateError> {
+        let index = match self
+            .genes
+            .iter()
+            .position(|x| x.enabled && x.innovation == innovation)
+        {
+            Some(i) => i,
+            None => return Err(MutateError::UnknownEdge),
+        };
 
-            if is_recursive_checker(seen_nodes.clone(), &self.genes, i) {
-                return true;
-            }
+        // Splitting the same edge always gives the same node and innovations,
+        // so genomes that make the same split still line up in crossover
+        let (node, in_innovation, out_innovation) = self
+            .trainer
+            .innovator
+            .new_node_innovation(innovation, self.trainer.layout.hidden_start());
+        if self.genes.iter().any(|x| x.innovation == in_innovation) {
+            return Err(MutateError::AlreadySplit);
         }
 
-        false
+        // Adding either edge by hand (in another genome) has to give the same innovation too
+        let old = self.genes[index];
+        let innovator = &self.trainer.innovator;
+        innovator.register_edge((old.node_in, node), in_innovation);
+        innovator.register_edge((node, old.node_out), out_innovation);
+
+        let generation = self.trainer.generation();
+        self.genes[index].enabled = false;
+        self.insert_gene(Gene {
+            node_in: old.node_in,
+            node_out: node,
+            weight: 1.0,
+            enabled: true,
+            innovation: in_innovation,
+            generation_added: generation,
+        });
+        self.insert_gene(Gene {
+            node_in: node,
+            node_out: old.node_out,
+            weight: old.weight,
+            enabled: true,
+            innovation: out_innovation,
+            generation_added: generation,
+        });
+        self.invalidate_topology();
+        Ok(())
+    }
+
+    // Adds a gene while keeping the genes sorted by innovation
+    fn insert_gene(&mut self, gene: Gene) {
+        let index = self
+            .genes
+            .partition_point(|x| x.innovation < gene.innovation);
+        self.genes.insert(index, gene);
+    }
+
+    /// Gets rid of genes that can't change the output of the network.
+    /// Genes going into a node with no enabled path to an output are disabled, so they aren't simulated.
+    /// Genes are only removed (those ones and every other disabled gene) if `drop_disabled` is set,
+    /// as crossover, [`Genome::distance`] and [`Genome::split_edge`] use them to line genomes up.
+    pub fn prune(&mut self, drop_disabled: bool) {
+        network::prune(&mut self.genes, self.trainer.layout, drop_disabled);
+        self.invalidate_topology();
+    }
+
+    // Checks if adding this many nodes and edges would stay within `max_nodes` and `max_edges`
+    fn can_grow(&self, nodes: usize, edges: usize) -> bool {
+        let config = self.trainer.config();
+        config
+            .max_nodes
+            .is_none_or(|x| self.node_count() + nodes <= x)
+            && config
+                .max_edges
+                .is_none_or(|x| self.genes.len() + edges <= x)
     }
 
     pub fn mutate(&self) -> Self {
+        self.mutate_scaled(1.0)
+    }
+
+    /// Like [`Genome::mutate`] but the chances to add a node or an edge are multiplied by `scale` (capped at 1).
+    /// Used for adaptive mutation, see [`crate::species::Specie::mutation_scale`].
+    pub fn mutate_scaled(&self, scale: f32) -> Self {
         let mut rng = thread_rng();
         let mut this = self.clone();
+        let config = self.trainer.config();
+
+        // Enable Edges
+        // Done before disabling so edges aren't turned off and back on in one go,
+        // edges that would make a loop are left disabled (unless loops aren't being checked for)
+        let mut changed = false;
+        for i in 0..this.genes.len() {
+            if this.genes[i].enabled || !rng.gen_bool(config.mutate_enable_edge.into()) {
+                continue;
+            }
+
+            this.genes[i].enabled = true;
+            if config.recursion_check == RecursionCheck::Always && this.is_recursive() {
+                this.genes[i].enabled = false;
+            } else {
+                changed = true;
+            }
+        }
 
         // Mutate Weights
         for i in this.genes.iter_mut().filter(|x| x.enabled) {
-            if rng.gen_bool(self.trainer.config.mutate_weight.into()) {
-                if rng.gen_bool(self.trainer.config.mutate_weight.into()) {
-                    i.weight = rng.gen_range(-1f32..=1f32);
+            if rng.gen_bool(config.mutate_weight.into()) {
+                if rng.gen_bool(config.mutate_weight_reset.into()) {
+                    i.weight = config.weight_init.sample(&mut rng);
                     continue;
                 }
-                i.weight *= rng.gen_range(-1f32..=1f32);
+                i.weight += rng.gen_range(-1f32..=1f32) * config.weight_perturb_power;
             }
 
-            if rng.gen_bool(self.trainer.config.mutate_disable_edge.into()) {
+            if rng.gen_bool(config.mutate_disable_edge.into()) {
                 i.enabled = false;
+                changed = true;
             }
         }
 
+        // Prune Edges
+        // Disabling edges can't make a loop, so this doesn't need checking
+        if config.mutate_prune_zero_weights {
+            for i in this
+                .genes
+                .iter_mut()
+                .filter(|x| x.enabled && x.weight.abs() < config.prune_weight_epsilon)
+            {
+                i.enabled = false;
+                changed = true;
+            }
+        }
+
+        if changed {
+            this.invalidate_topology();
+        }
+
         // Add Edge
-        if rng.gen_bool(self.trainer.config.mutate_add_edge.into()) {
-            // Make a vec of hidden nodes
-            let mut hidden = HashSet::new();
+        if this.can_grow(0, 1)
+            && rng.gen_bool((config.mutate_add_edge * scale).clamp(0.0, 1.0).into())
+        {
+            // Make a vec of every node
+            // Sensors and outputs are always included, even if no gene connects them
+            let mut nodes = HashSet::new();
+            nodes.extend(0..self.trainer.layout.hidden_start());
             for i in &self.genes {
-                hidden.insert(i.node_out);
+                nodes.insert(i.node_in);
+                nodes.insert(i.node_out);
             }
-            let hidden = hidden.iter().collect::<Vec<_>>();
+            let nodes = nodes.into_iter().collect::<Vec<_>>();
 
-            for _ in 0..self.trainer.config.mutate_add_edge_tries {
+            for _ in 0..config.mutate_add_edge_tries {
                 // Generate indices
-                let a = **hidden.choose(&mut rng).unwrap();
-                let b = **hidden.choose(&mut rng).unwrap();
-
-                // Verify Indexes
-                // Make sure not pointing to the same node twice, going in order of sensor => (hidden) => output
-                // not the other way around and the connection would not make a recursive connection
-                if a == b
-                    || this.genes.iter().any(|x| x.connects(a, b))
-                    || this.classify_node(a) == NodeType::Output
-                    || this.classify_node(b) == NodeType::Sensor
-                    || this.would_be_recursive(a, b)
+                let (Some(&a), Some(&b)) = (nodes.choose(&mut rng), nodes.choose(&mut rng)) else {
+                    break;
+                };
+
+                if this
+                    .add_connection(a, b, config.weight_init.sample(&mut rng))
+                    .is_ok()
                 {
-                    continue;
+                    log::debug!("Genome {}: added edge {} -> {}", self.id, a, b);
+                    break;
                 }
+            }
+        }
 
-                this.genes.push(Gene::random(self.trainer.clone(), a, b));
-                break;
+        // Mutate Activation
+        if rng.gen_bool(config.mutate_activation.into()) {
+            let node = this
+                .genes
+                .iter()
+                .flat_map(|x| [x.node_in, x.node_out])
+                .filter(|x| this.classify_node(*x) == NodeType::Hidden)
+                .choose(&mut rng);
+
+            if let Some(node) = node {
+                let current = this
+                    .node_activations
+                    .get(&node)
+                    .copied()
+                    .unwrap_or(config.hidden_activation);
+                let new = Activation::ALL
+                    .into_iter()
+                    .filter(|x| *x != current)
+                    .choose(&mut rng);
+                if let Some(new) = new {
+                    log::debug!(
+                        "Genome {}: node {} activation {} -> {}",
+                        self.id,
+                        node,
+                        current.name(),
+                        new.name()
+                    );
+                    this.node_activations.insert(node, new);
+                }
             }
         }
 
         // Add Node
-        if rng.gen_bool(self.trainer.config.mutate_add_node.into()) {
-            // let mut gene = if this.genes.len() < 15  && false{
-            //     dbg!((0..this.genes.len()).rev().map(|x| x + 1).collect::<Vec<_>>());
-            //     let weights =
-            //         WeightedIndex::new((0..this.genes.len()).rev().map(|x| x + 1)).unwrap();
-
-            //     &mut this.genes[weights.sample(&mut rng)]
-            // } else {
-            let mut gene = this
-                .genes
-                .iter_mut()
-                .filter(|x| x.enabled)
-                .choose(&mut rng)
-                .unwrap();
-            // };
-
-            let old_node_from = gene.node_in;
-            let old_node_to = gene.node_out;
-
-            gene.enabled = false;
-            this.genes.push(Gene {
-                node_in: old_node_from,
-                node_out: this.node_id,
-                weight: 1.0,
-                enabled: true,
-                innovation: self
-                    .trainer
-                    .innovator
-                    .new_edge((old_node_from, this.node_id)),
-            });
-            this.genes.push(Gene::random(
-                self.trainer.clone(),
-                this.node_id,
-                old_node_to,
-            ));
-            this.node_id += 1;
+        if this.can_grow(1, 2)
+            && rng.gen_bool((config.mutate_add_node * scale).clamp(0.0, 1.0).into())
+        {
+            // Genomes can start without any edges
+            let enabled = this.genes.iter().filter(|x| x.enabled).collect::<Vec<_>>();
+            let index = match config.add_node_selection {
+                // Small genomes are biased toward splitting older edges.
+                // Otherwise the newest edges (the ones just made by splitting) keep getting split,
+                // making long chains of nodes instead of spreading structure around the network.
+                // Each edge is weighted by its age in generations plus one, so new edges can still be picked.
+                AddNodeSelection::OlderBiased
+                    if this.genes.len() < config.add_node_young_bias_threshold
+                        && !enabled.is_empty() =>
+                {
+                    let generation = self.trainer.generation();
+                    let weights = enabled
+                        .iter()
+                        .map(|x| generation.saturating_sub(x.generation_added) + 1);
+                    Some(WeightedIndex::new(weights).unwrap().sample(&mut rng))
+                }
+                _ => (0..enabled.len()).choose(&mut rng),
+            };
+            let innovation = index.map(|x| enabled[x].innovation);
+
+            // Fails if this genome has already split the edge (it was re-enabled since)
+            if let Some(innovation) = innovation {
+                if this.split_edge(innovation).is_ok() {
+                    log::debug!("Genome {}: split edge #{}", self.id, innovation);
+                }
+            }
         }
 
         this
     }
 
-    pub fn crossover(&self, other: &Self) -> Self {
+    /// Makes a child genome, `fitness` is the fitness of (self, other).
+    /// Matching genes come from either parent and the rest come from the fitter one.
+    pub fn crossover(&self, other: &Self, fitness: (f32, f32)) -> Self {
         let mut rng = thread_rng();
+        let config = self.trainer.config();
         let mut genes = Vec::with_capacity(self.genes.len().max(other.genes.len()));
 
         let (matching, self_genes, other_genes) = gene_diff(&self.genes, &other.genes);
@@ -323,20 +659,22 @@ impl Genome {
         // Add matching
         for i in matching {
             let mut gene = *if rng.gen_bool(0.4) { i.0 } else { i.1 };
-            if !gene.enabled && !rng.gen_bool(self.trainer.config.crossover_keep_disabled.into()) {
-                gene.enabled = true;
+            if rng.gen_bool(config.crossover_blend.into()) {
+                let t = rng.gen_range(0f32..=1f32);
+                gene.weight = i.0.weight + (i.1.weight - i.0.weight) * t;
+            }
+
+            // If either parent has the gene disabled there is a `crossover_keep_disabled` chance it is disabled in the child,
+            // otherwise it is enabled
+            if !i.0.enabled || !i.1.enabled {
+                gene.enabled = !rng.gen_bool(config.crossover_keep_disabled.into());
             }
 
             genes.push(gene);
         }
 
         // Add nonmatching
-        let fitter_nonmatching = match self
-            .fitness
-            .unwrap()
-            .partial_cmp(&other.fitness.unwrap())
-            .unwrap()
-        {
+        let fitter_nonmatching = match fitness_cmp(fitness.0, fitness.1) {
             Ordering::Greater => self_genes,
             Ordering::Less => other_genes,
             _ => [self_genes, other_genes]
@@ -345,6 +683,21 @@ impl Genome {
                 .to_owned(),
         };
         genes.extend(fitter_nonmatching.iter().copied());
+        genes.sort_by_key(|x| x.innovation);
+
+        // Each changed activation comes from a random parent, where a parent without one gives the default
+        let mut node_activations = BTreeMap::new();
+        let nodes = self
+            .node_activations
+            .keys()
+            .chain(other.node_activations.keys())
+            .collect::<BTreeSet<_>>();
+        for i in nodes {
+            let parent = if rng.gen_bool(0.5) { self } else { other };
+            if let Some(activation) = parent.node_activations.get(i) {
+                node_activations.insert(*i, *activation);
+            }
+        }
 
         Genome {
             trainer: self.trainer.clone(),
@@ -352,86 +705,368 @@ impl Genome {
             species: None,
             fitness: None,
             genes,
-            node_id: self.node_id.max(other.node_id),
+            node_activations,
+            topology: OnceLock::new(),
         }
     }
 
+    /// Runs the genome, see [`NodeLayout`](crate::network::NodeLayout) for the order sensors take their values in.
+    /// Outputs with nothing connected to them give the output activation of 0, see [`Network::simulate`]
     pub fn simulate(&self, sensors: &[f32]) -> Vec<f32> {
-        let mut out = Vec::with_capacity(self.trainer.outputs);
-        let node_tester = Rc::new(NodeTester::from_genome(self, sensors));
+        self.simulate_profiled(sensors).0
+    }
+
+    /// Same as [`Genome::simulate`], but also returns how many nodes and edges were worked through.
+    /// Useful for finding genomes that are expensive to run.
+    pub fn simulate_profiled(&self, sensors: &[f32]) -> (Vec<f32>, SimulateStats) {
+        let mut ctx = SimulateCtx::new();
+        let stats = self.simulate_ctx(&mut ctx, sensors);
+        (ctx.outputs, stats)
+    }
 
-        for i in self.trainer.inputs..self.trainer.inputs + self.trainer.outputs {
-            out.push(node_tester.clone().prop(i));
+    /// Same as [`Genome::simulate`], but reuses the buffers in `ctx` instead of allocating new ones.
+    /// When running a genome many times (like over a dataset) make one [`SimulateCtx`] and pass it to every run.
+    /// The outputs are borrowed from `ctx`, so they are overwritten by the next run.
+    pub fn simulate_with<'a>(&self, ctx: &'a mut SimulateCtx, sensors: &[f32]) -> &'a [f32] {
+        self.simulate_ctx(ctx, sensors);
+        &ctx.outputs
+    }
+
+    fn simulate_ctx(&self, ctx: &mut SimulateCtx, sensors: &[f32]) -> SimulateStats {
+        let config = self.trainer.config();
+        network::simulate_ordered(
+            ctx,
+            &self.genes,
+            self.topology(),
+            self.trainer.layout,
+            config.activations(),
+            &self.node_activations,
+            config.value_limit,
+            sensors,
+        )
+    }
+
+    /// Gets the order nodes are evaluated in, this is cached until the structure changes
+    pub fn topology(&self) -> &[usize] {
+        self.topology
+            .get_or_init(|| network::topological_order(&self.genes, self.trainer.layout))
+    }
+
+    /// Gets the network as a weight matrix, for running it with matrix based inference.
+    /// Returns the node ids in topological order and a matrix where `matrix[i][j]` is the weight of the enabled edge
+    /// from node `ids[i]` to node `ids[j]`, or 0 if there isn't one. Because of the ordering the matrix is strictly upper triangular.
+    /// Every sensor and output is included, along with any hidden node connected by an enabled edge.
+    /// Activations are not included, see [`Genome::node_activations`].
+    pub fn adjacency_matrix(&self) -> (Vec<usize>, Vec<Vec<f32>>) {
+        let ids = network::full_order(&self.genes, self.trainer.layout);
+        let index = ids
+            .iter()
+            .enumerate()
+            .map(|(i, &e)| (e, i))
+            .collect::<HashMap<_, _>>();
+
+        let mut matrix = vec![vec![0.0; ids.len()]; ids.len()];
+        for i in self.genes.iter().filter(|x| x.enabled) {
+            // Nodes in a loop aren't in the order
+            if let (Some(&from), Some(&to)) = (index.get(&i.node_in), index.get(&i.node_out)) {
+                matrix[from][to] += i.weight;
+            }
         }
 
-        out
+        (ids, matrix)
     }
-}
 
-impl Debug for Genome {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Genome")
-            .field("genes", &self.genes)
-            .field("node_id", &self.node_id)
-            .field("id", &self.id)
-            .field("species", &self.species)
-            .field("fitness", &self.fitness)
-            .finish()
+    /// Clears the cached topology, needed after changing `genes` by hand
+    pub fn invalidate_topology(&mut self) {
+        self.topology = OnceLock::new();
+    }
+
+    /// Copies out the parts of the genome needed to run it, see [`Network`].
+    /// Use [`Network::cast`] to run it with something other than `f32`.
+    pub fn network(&self) -> Network {
+        Network {
+            layout: self.trainer.layout,
+            genes: self.genes.clone(),
+            activations: self.trainer.config().activations(),
+            node_activations: self.node_activations.clone(),
+            value_limit: self.trainer.config().value_limit,
+        }
     }
 }
 
-impl NodeTester {
-    fn from_genome(genome: &Genome, sensors: &[f32]) -> Self {
-        let mut nodes = HashMap::new();
-        let inputs = genome.trainer.inputs;
-        let outputs = genome.trainer.outputs;
-        debug_assert_eq!(sensors.len(), inputs);
+/// Summary of a set of edge weights, see [`Genome::weight_stats`].
+/// Everything is 0 if there are no edges.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WeightStats {
+    /// The number of edges
+    pub count: usize,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    /// The population standard deviation
+    pub std_dev: f32,
+}
 
-        for (i, e) in sensors.iter().enumerate().take(inputs) {
-            nodes.insert(i, Some(*e));
+impl WeightStats {
+    fn new(weights: impl Iterator<Item = f32> + Clone) -> Self {
+        let count = weights.clone().count();
+        if count == 0 {
+            return Self::default();
         }
 
-        for i in inputs..inputs + outputs {
-            nodes.insert(i, None);
-        }
+        let mean = weights.clone().sum::<f32>() / count as f32;
+        let variance = weights.clone().map(|x| (x - mean).powi(2)).sum::<f32>() / count as f32;
 
         Self {
-            nodes: RefCell::new(nodes),
-            genes: genome.clone(),
+            count,
+            min: weights.clone().fold(f32::MAX, f32::min),
+            max: weights.fold(f32::MIN, f32::max),
+            mean,
+            std_dev: variance.sqrt(),
         }
     }
+}
 
-    fn prop(self: Rc<Self>, to: usize) -> f32 {
-        let mut out = 0.0;
+#[cfg(feature = "petgraph")]
+impl Genome {
+    /// Builds a directed graph of the genome, with node weights being the node types and edge weights being the edge weights.
+    /// Disabled edges are only added if `include_disabled` is set.
+    pub fn to_petgraph(&self, include_disabled: bool) -> petgraph::graph::DiGraph<NodeType, f32> {
+        let mut graph = petgraph::graph::DiGraph::new();
+        let mut nodes = BTreeMap::new();
+
+        let mut ids = (0..self.trainer.layout.hidden_start()).collect::<BTreeSet<_>>();
+        ids.extend(self.genes.iter().flat_map(|x| [x.node_in, x.node_out]));
+        for i in ids {
+            nodes.insert(i, graph.add_node(self.classify_node(i)));
+        }
 
-        // Get nodes that connect to this one
-        for i in self
-            .genes
-            .genes
+        for i in self.genes.iter().filter(|x| x.enabled || include_disabled) {
+            graph.add_edge(nodes[&i.node_in], nodes[&i.node_out], i.weight);
+        }
+
+        graph
+    }
+}
+
+#[cfg(feature = "ndarray")]
+impl Genome {
+    /// Runs the genome on a whole batch of inputs at once, with one row per sample and one column per sensor.
+    /// Returns one row of outputs per sample, the same as calling [`Genome::simulate`] on each row
+    /// (other than rounding, as the edges into a node are summed in a different order).
+    ///
+    /// Nodes are grouped into layers by their longest path from a sensor, then each layer is worked out
+    /// with one matrix multiply from the values of every node before it.
+    /// This is a lot faster than simulating each sample on its own for big datasets.
+    /// Only feedforward genomes are supported, recursive ones give [`GenomeError::Recursive`].
+    /// `inputs` needs a column for every sensor, otherwise this gives [`GenomeError::SensorCount`].
+    pub fn simulate_ndarray(
+        &self,
+        inputs: ndarray::ArrayView2<f32>,
+    ) -> Result<ndarray::Array2<f32>, GenomeError> {
+        use ndarray::{s, Array2, Axis};
+
+        let layout = self.trainer.layout;
+        if inputs.ncols() != layout.inputs {
+            return Err(GenomeError::SensorCount);
+        }
+        if self.is_recursive() {
+            return Err(GenomeError::Recursive);
+        }
+
+        // Every sensor is layer 0, other nodes are one after the deepest node going into them.
+        // Nodes left out of the topology (not on a path from a sensor to an output) act as 0, like when simulating.
+        let order = self.topology();
+        let mut layers = HashMap::new();
+        layers.extend(layout.sensor_range().map(|x| (x, 0)));
+        for &i in order
             .iter()
-            .filter(|x| x.enabled && x.node_out == to)
+            .filter(|&&x| self.classify_node(x) != NodeType::Sensor)
         {
-            // Check if the node this gene is refrencing is a sensor
-            // If so add that to the out
-            // Else recursively call prop function
-            let new_self = self.clone();
-            let mut node_map = self.nodes.borrow_mut();
-            let ref_node = node_map.entry(i.node_in).or_default();
-            let val = match ref_node {
-                Some(i) => *i,
-                None => {
-                    drop(node_map);
-                    new_self.prop(i.node_in)
-                }
-            };
-            out += val * i.weight;
+            let layer = self
+                .genes
+                .iter()
+                .filter(|x| x.enabled && x.node_out == i)
+                .filter_map(|x| layers.get(&x.node_in))
+                .max()
+                .map_or(1, |x| x + 1);
+            layers.insert(i, layer);
         }
 
-        if self.genes.classify_node(to) == NodeType::Hidden {
-            sigmoid(out);
+        // Columns are the nodes sorted by layer, so every edge goes from an earlier column to a later one
+        let mut ids = layers.keys().copied().collect::<Vec<_>>();
+        ids.sort_by_key(|x| (layers[x], *x));
+        let column = ids
+            .iter()
+            .enumerate()
+            .map(|(i, &e)| (e, i))
+            .collect::<HashMap<_, _>>();
+
+        let mut weights = Array2::<f32>::zeros((ids.len(), ids.len()));
+        for i in self.genes.iter().filter(|x| x.enabled) {
+            if let (Some(&from), Some(&to)) = (column.get(&i.node_in), column.get(&i.node_out)) {
+                weights[[from, to]] += i.weight;
+            }
         }
 
-        out
+        let config = self.trainer.config();
+        let activations = config.activations();
+        let value_limit = config.value_limit;
+        let mut values = Array2::<f32>::zeros((inputs.nrows(), ids.len()));
+        values.slice_mut(s![.., ..layout.inputs]).assign(&inputs);
+
+        let mut start = layout.inputs;
+        while start < ids.len() {
+            let layer = layers[&ids[start]];
+            let end = start
+                + ids[start..]
+                    .iter()
+                    .take_while(|x| layers[x] == layer)
+                    .count();
+
+            let sums = values
+                .slice(s![.., ..start])
+                .dot(&weights.slice(s![..start, start..end]));
+            for (i, mut sums) in sums.axis_iter(Axis(1)).map(|x| x.to_owned()).enumerate() {
+                let id = ids[start + i];
+                let node_type = self.classify_node(id);
+                let activation = match self.node_activations.get(&id) {
+                    Some(x) if node_type == NodeType::Hidden => *x,
+                    _ => activations.get(node_type),
+                };
+
+                // Same as simulating, NaNs become 0 and values are clamped
+                sums.mapv_inplace(|x| {
+                    let out = activation.apply(x);
+                    let out = if out.is_nan() { 0.0 } else { out };
+                    out.clamp(-value_limit, value_limit)
+                });
+                values.column_mut(start + i).assign(&sums);
+            }
+
+            start = end;
+        }
+
+        let mut out = Array2::<f32>::zeros((inputs.nrows(), layout.outputs));
+        for (i, id) in layout.output_range().enumerate() {
+            out.column_mut(i).assign(&values.column(column[&id]));
+        }
+        for mut row in out.rows_mut() {
+            if let Some(row) = row.as_slice_mut() {
+                activations.output_mode.apply(row);
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Why a structural change to a genome could not be made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MutateError {
+    /// The edge would connect a node to itself
+    SameNode,
+    /// The nodes are already connected
+    AlreadyConnected,
+    /// The edge would come out of an output node
+    FromOutput,
+    /// The edge would go into a sensor node
+    IntoSensor,
+    /// The edge would make a loop
+    Recursive,
+    /// No enabled edge has this innovation
+    UnknownEdge,
+    /// This genome has already split the edge
+    AlreadySplit,
+}
+
+impl Display for MutateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MutateError::SameNode => "edge would connect a node to itself",
+            MutateError::AlreadyConnected => "nodes are already connected",
+            MutateError::FromOutput => "edge would come out of an output node",
+            MutateError::IntoSensor => "edge would go into a sensor node",
+            MutateError::Recursive => "edge would make a loop",
+            MutateError::UnknownEdge => "no enabled edge has this innovation",
+            MutateError::AlreadySplit => "edge has already been split",
+        })
+    }
+}
+
+impl Error for MutateError {}
+
+/// Why [`Genome::from_genes`] (or [`Genome::simulate_ndarray`]) failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenomeError {
+    /// A gene connects a node to itself
+    SameNode,
+    /// A gene goes into a sensor node
+    IntoSensor,
+    /// A gene comes out of an output node
+    FromOutput,
+    /// Two genes have the same innovation
+    DuplicateInnovation,
+    /// The enabled genes make a loop
+    Recursive,
+    /// The inputs don't have one value for every sensor
+    SensorCount,
+}
+
+impl Display for GenomeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            GenomeError::SameNode => "gene connects a node to itself",
+            GenomeError::IntoSensor => "gene goes into a sensor node",
+            GenomeError::FromOutput => "gene comes out of an output node",
+            GenomeError::DuplicateInnovation => "two genes have the same innovation",
+            GenomeError::Recursive => "genes make a loop",
+            GenomeError::SensorCount => "wrong number of sensors",
+        })
+    }
+}
+
+impl Error for GenomeError {}
+
+impl Debug for Genome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Genome")
+            .field("genes", &self.genes)
+            .field("node_activations", &self.node_activations)
+            .field("id", &self.id)
+            .field("species", &self.species)
+            .field("fitness", &self.fitness)
+            .finish()
+    }
+}
+
+/// A short human readable summary, use [`Genome::to_mermaid`] for a Mermaid graph
+impl Display for Genome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Genome {} | NODES: {} | EDGES: {}/{} | SPECIES: {} | FITNESS: {}",
+            self.id,
+            self.node_count(),
+            self.enabled_edge_count(),
+            self.genes.len(),
+            self.species
+                .map(|x| x.to_string())
+                .unwrap_or_else(|| "None".to_owned()),
+            self.fitness
+                .map(|x| format!("{x:.3}"))
+                .unwrap_or_else(|| "None".to_owned())
+        )?;
+
+        for i in &self.genes {
+            write!(f, "  {} -> {} (w={:+.2})", i.node_in, i.node_out, i.weight)?;
+            if !i.enabled {
+                write!(f, " [disabled]")?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
     }
 }
 
@@ -440,9 +1075,10 @@ impl Gene {
         Self {
             node_in: from,
             node_out: to,
-            weight: thread_rng().gen_range(-1f32..=1f32),
+            weight: trainer.config().weight_init.sample(&mut thread_rng()),
             enabled: true,
             innovation: trainer.innovator.new_edge((from, to)),
+            generation_added: trainer.generation(),
         }
     }
 
@@ -451,40 +1087,596 @@ impl Gene {
     }
 }
 
-fn is_recursive_checker(seen_nodes: HashSet<usize>, genes: &[Gene], index: usize) -> bool {
-    for i in genes.iter().filter(|x| x.enabled && x.node_in == index) {
-        let mut seen_nodes = seen_nodes.clone();
-        let seen = seen_nodes.contains(&i.node_out);
-        seen_nodes.insert(i.node_out);
-        if seen || is_recursive_checker(seen_nodes, genes, i.node_out) {
-            return true;
+// Checks every gene can exist in `layout`, see `Genome::from_genes`
+pub(crate) fn check_layout(genes: &[Gene], layout: NodeLayout) -> Result<(), GenomeError> {
+    for i in genes {
+        if i.node_in == i.node_out {
+            return Err(GenomeError::SameNode);
+        }
+
+        if layout.classify(i.node_out) == NodeType::Sensor {
+            return Err(GenomeError::IntoSensor);
+        }
+
+        if layout.classify(i.node_in) == NodeType::Output {
+            return Err(GenomeError::FromOutput);
         }
     }
 
-    false
+    Ok(())
 }
 
 // -> (Matching Genes, A Genes, B Genes)
+// Both gene lists have to be sorted by innovation
 fn gene_diff<'a>(
     a: &'a [Gene],
     b: &'a [Gene],
 ) -> (Vec<(&'a Gene, &'a Gene)>, Vec<&'a Gene>, Vec<&'a Gene>) {
     let mut matching = Vec::new();
     let mut a_extra = Vec::new();
-    let mut b_extra = b.iter().collect::<Vec<_>>();
+    let mut b_extra = Vec::new();
+
+    let (mut a, mut b) = (a.iter().peekable(), b.iter().peekable());
+    loop {
+        match (a.peek(), b.peek()) {
+            (Some(x), Some(y)) => match x.innovation.cmp(&y.innovation) {
+                Ordering::Equal => matching.push((a.next().unwrap(), b.next().unwrap())),
+                Ordering::Less => a_extra.push(a.next().unwrap()),
+                Ordering::Greater => b_extra.push(b.next().unwrap()),
+            },
+            (Some(_), None) => a_extra.extend(a.by_ref()),
+            (None, Some(_)) => b_extra.extend(b.by_ref()),
+            (None, None) => break,
+        }
+    }
 
-    for i in a {
-        let matched = match b.iter().find(|x| x.innovation == i.innovation) {
-            Some(i) => i,
-            None => {
-                a_extra.push(i);
+    (matching, a_extra, b_extra)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashMap};
+    use std::sync::{atomic::Ordering, Arc};
+
+    use crate::{
+        config::{AddNodeSelection, Config},
+        network::{Activation, Network, NodeType, OutputMode},
+        test_util::{mutated_genomes, trainer},
+        trainer::Trainer,
+    };
+
+    use super::{Gene, Genome, MutateError};
+
+    fn gene(node_in: usize, node_out: usize, weight: f32, innovation: usize) -> Gene {
+        Gene {
+            node_in,
+            node_out,
+            weight,
+            enabled: true,
+            innovation,
+            generation_added: 0,
+        }
+    }
+
+    #[test]
+    fn prune_keeps_output_and_alignment() {
+        // 2 sensors, 1 output (2) and a hidden node from splitting 0 -> 2, whose edge to the output is then disabled
+        let trainer = Arc::new(Trainer::new(2, 1));
+        let mut genome =
+            Genome::from_genes(trainer, vec![gene(0, 2, 0.5, 0), gene(1, 2, -0.3, 1)]).unwrap();
+        genome.split_edge(0).unwrap();
+        let out = genome.genes.iter().position(|x| x.innovation == 3).unwrap();
+        genome.genes[out].enabled = false;
+        genome.invalidate_topology();
+
+        let inputs = [[0.0, 0.0], [1.0, 0.5], [-2.0, 3.0]];
+        let outputs = inputs.map(|x| genome.simulate(&x));
+        let innovations = |x: &Genome| x.genes.iter().map(|x| x.innovation).collect::<Vec<_>>();
+
+        let mut kept = genome.clone();
+        kept.prune(false);
+        assert_eq!(innovations(&kept), [0, 1, 2, 3]);
+        assert!(kept
+            .genes
+            .iter()
+            .skip(1)
+            .all(|x| x.enabled == (x.innovation == 1)));
+        assert_eq!(inputs.map(|x| kept.simulate(&x)), outputs);
+        // The split is still there, so making it again can't add duplicate innovations
+        kept.genes[0].enabled = true;
+        assert_eq!(kept.split_edge(0), Err(MutateError::AlreadySplit));
+
+        let mut dropped = genome.clone();
+        dropped.prune(true);
+        assert_eq!(innovations(&dropped), [1]);
+        assert_eq!(inputs.map(|x| dropped.simulate(&x)), outputs);
+    }
+
+    #[test]
+    fn prune_keeps_output_of_mutated_genomes() {
+        let trainer = trainer(|x| {
+            x.mutate_add_node = 0.5;
+            x.mutate_disable_edge = 0.2;
+        });
+        for genome in mutated_genomes(&trainer, 40) {
+            let outputs = genome.simulate(&[1.0, 0.5, -0.5]);
+            for drop_disabled in [false, true] {
+                let mut pruned = genome.clone();
+                pruned.prune(drop_disabled);
+                assert_eq!(pruned.simulate(&[1.0, 0.5, -0.5]), outputs);
+            }
+        }
+    }
+
+    #[test]
+    fn add_edge_connects_sensors() {
+        // With no genes the only nodes are the sensors and outputs
+        let trainer = trainer(|x| {
+            x.mutate_add_edge = 1.0;
+            x.mutate_add_node = 0.0;
+        });
+        let mut genome = Genome::from_genes(trainer, Vec::new()).unwrap();
+        for _ in 0..100 {
+            genome = genome.mutate();
+        }
+
+        let mut edges = genome
+            .genes
+            .iter()
+            .map(|x| (x.node_in, x.node_out))
+            .collect::<Vec<_>>();
+        edges.sort();
+        assert_eq!(edges, [(0, 3), (0, 4), (1, 3), (1, 4), (2, 3), (2, 4)]);
+    }
+
+    #[test]
+    fn linear_output_is_weighted_input() {
+        let config = Config {
+            output_activation: Activation::Linear,
+            ..Config::default()
+        };
+        let trainer = Arc::new(Trainer::new_with_config(1, 1, config));
+        let genome = Genome::from_genes(trainer, vec![gene(0, 1, 0.75, 0)]).unwrap();
+
+        for input in [0.0, 1.0, -2.0, 3.5] {
+            assert_eq!(genome.simulate(&[input]), [0.75 * input]);
+        }
+    }
+
+    #[test]
+    fn softmax_outputs() {
+        let config = Config {
+            output_activation: Activation::Linear,
+            output_mode: OutputMode::Softmax,
+            ..Config::default()
+        };
+        let trainer = Arc::new(Trainer::new_with_config(1, 2, config));
+        let genes = vec![gene(0, 1, 1.0, 0), gene(0, 2, 2.0, 1)];
+        let genome = Genome::from_genes(trainer, genes).unwrap();
+
+        // The outputs are 1 and 2 before the softmax
+        let outputs = genome.simulate(&[1.0]);
+        let expected = [1.0 / (1.0 + 1f32.exp()), 1.0 / (1.0 + (-1f32).exp())];
+        for (output, expected) in outputs.iter().zip(expected) {
+            assert!((output - expected).abs() < 1e-6, "{outputs:?}");
+        }
+
+        for input in [-5.0, 0.0, 0.3, 40.0] {
+            let sum = genome.simulate(&[input]).iter().sum::<f32>();
+            assert!((sum - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn split_edges_share_innovations_with_added_edges() {
+        let trainer = Arc::new(Trainer::new(2, 1));
+        let genes = vec![gene(0, 2, 0.5, 0), gene(1, 2, -0.3, 1)];
+        let mut split = Genome::from_genes(trainer.clone(), genes.clone()).unwrap();
+        split.split_edge(0).unwrap();
+        let node = split.genes.iter().map(|x| x.node_out).max().unwrap();
+
+        // Another genome connecting the same nodes by hand gets the same innovations
+        let mut added = Genome::from_genes(trainer, genes).unwrap();
+        added.add_connection(0, node, 1.0).unwrap();
+        added.add_connection(node, 2, 1.0).unwrap();
+
+        let innovations = |genome: &Genome| {
+            genome
+                .genes
+                .iter()
+                .filter(|x| x.node_in == node || x.node_out == node)
+                .map(|x| (x.node_in, x.node_out, x.innovation))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(innovations(&added), innovations(&split));
+        assert_eq!(innovations(&added).len(), 2);
+    }
+
+    // Everything about a gene, for comparing them
+    fn key(gene: &Gene) -> (usize, usize, usize, u32, bool) {
+        (
+            gene.innovation,
+            gene.node_in,
+            gene.node_out,
+            gene.weight.to_bits(),
+            gene.enabled,
+        )
+    }
+
+    #[test]
+    fn crossover_only_inherits_parent_genes() {
+        let trainer = trainer(|x| {
+            x.mutate_add_node = 0.5;
+            x.mutate_disable_edge = 0.2;
+        });
+        let genomes = mutated_genomes(&trainer, 30);
+
+        for (a, b) in genomes.iter().zip(genomes.iter().rev()) {
+            for fitness in [(1.0, 0.0), (0.0, 1.0), (0.5, 0.5)] {
+                let child = a.crossover(b, fitness);
+                for gene in &child.genes {
+                    // Enabled can change if either parent had the gene disabled
+                    let from_parent = a.genes.iter().chain(&b.genes).any(|x| {
+                        let (mut x, mut gene) = (key(x), key(gene));
+                        x.4 = false;
+                        gene.4 = false;
+                        x == gene
+                    });
+                    assert!(from_parent, "{gene:?} isn't from a parent");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn crossover_aligns_genes() {
+        // Innovations 0 and 1 match, 2 and 3 are disjoint in `a` and 4 and 5 are excess in `b`
+        let trainer = trainer(|x| x.crossover_blend = 0.0);
+        let a = vec![
+            gene(0, 3, 0.1, 0),
+            gene(1, 3, 0.2, 1),
+            gene(0, 5, 0.3, 2),
+            gene(5, 3, 0.4, 3),
+        ];
+        let b = vec![
+            gene(0, 3, -0.1, 0),
+            gene(1, 3, -0.2, 1),
+            gene(2, 4, -0.5, 4),
+            gene(1, 4, -0.6, 5),
+        ];
+        let a = Genome::from_genes(trainer.clone(), a).unwrap();
+        let b = Genome::from_genes(trainer, b).unwrap();
+
+        let mut from_a = [false; 2];
+        let mut from_b = [false; 2];
+        for (fitness, fitter) in [((1.0, 0.0), &a), ((0.0, 1.0), &b)] {
+            for _ in 0..100 {
+                let child = a.crossover(&b, fitness);
+                let innovations = child.genes.iter().map(|x| x.innovation).collect::<Vec<_>>();
+                let expected = [0, 1]
+                    .into_iter()
+                    .chain(fitter.genes.iter().skip(2).map(|x| x.innovation))
+                    .collect::<Vec<_>>();
+                assert_eq!(innovations, expected);
+
+                // Matching genes come from either parent, the rest from the fitter one
+                for (i, gene) in child.genes.iter().enumerate() {
+                    if i < 2 {
+                        from_a[i] |= key(gene) == key(&a.genes[i]);
+                        from_b[i] |= key(gene) == key(&b.genes[i]);
+                        assert!(key(gene) == key(&a.genes[i]) || key(gene) == key(&b.genes[i]));
+                    } else {
+                        assert_eq!(key(gene), key(&fitter.genes[i]));
+                    }
+                }
+            }
+        }
+        assert_eq!((from_a, from_b), ([true; 2], [true; 2]));
+    }
+
+    #[test]
+    fn crossover_disabled_combinations() {
+        for keep_disabled in [0.0, 1.0] {
+            let trainer = trainer(|x| x.crossover_keep_disabled = keep_disabled);
+            for (a_enabled, b_enabled) in
+                [(true, true), (true, false), (false, true), (false, false)]
+            {
+                let parent = |enabled| {
+                    let mut gene = gene(0, 3, 0.5, 0);
+                    gene.enabled = enabled;
+                    Genome::from_genes(trainer.clone(), vec![gene]).unwrap()
+                };
+                let (a, b) = (parent(a_enabled), parent(b_enabled));
+
+                // Disabled in either parent means disabled with a `crossover_keep_disabled` chance
+                let expected = a_enabled && b_enabled || keep_disabled == 0.0;
+                for _ in 0..20 {
+                    assert_eq!(a.crossover(&b, (1.0, 0.0)).genes[0].enabled, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn enable_edge_enables_every_gene() {
+        let trainer = trainer(|x| {
+            x.mutate_add_node = 0.5;
+            x.mutate_disable_edge = 0.3;
+        });
+        let genomes = mutated_genomes(&trainer, 40);
+        trainer
+            .set_config(Config {
+                mutate_enable_edge: 1.0,
+                mutate_add_node: 0.0,
+                ..Config::default()
+            })
+            .unwrap();
+
+        let mut disabled = 0;
+        for genome in genomes {
+            // Edges that would make a loop are left disabled
+            let mut enabled = genome.clone();
+            enabled.genes.iter_mut().for_each(|x| x.enabled = true);
+            enabled.invalidate_topology();
+            if enabled.is_recursive() {
                 continue;
             }
+
+            disabled += genome.genes.iter().filter(|x| !x.enabled).count();
+            assert!(genome.mutate().genes.iter().all(|x| x.enabled));
+        }
+        assert!(disabled > 0);
+    }
+
+    #[test]
+    fn growth_stops_at_the_limits() {
+        let trainer = trainer(|x| {
+            x.mutate_add_node = 1.0;
+            x.mutate_add_edge = 1.0;
+        });
+        let mut genome = Genome::new(trainer.clone());
+        for _ in 0..3 {
+            genome = genome.mutate();
+        }
+
+        let (nodes, edges) = (genome.node_count(), genome.genes.len() + 4);
+        trainer
+            .set_config(Config {
+                max_nodes: Some(nodes),
+                max_edges: Some(edges),
+                ..trainer.config().as_ref().clone()
+            })
+            .unwrap();
+        for _ in 0..200 {
+            genome = genome.mutate();
+            assert_eq!(genome.node_count(), nodes);
+            assert!(genome.genes.len() <= edges);
+        }
+    }
+
+    #[test]
+    fn activation_mutation_keeps_topology() {
+        let trainer = trainer(|x| {
+            x.mutate_activation = 1.0;
+            x.mutate_weight = 0.0;
+            x.mutate_add_edge = 0.0;
+            x.mutate_add_node = 0.0;
+        });
+        let mut genome = Genome::from_genes(trainer.clone(), vec![gene(0, 3, 0.5, 0)]).unwrap();
+        genome.split_edge(0).unwrap();
+        let node = genome.genes[1].node_out;
+
+        let mut current = trainer.config().hidden_activation;
+        for _ in 0..20 {
+            let mutated = genome.mutate();
+            let genes = |x: &Genome| x.genes.iter().map(key).collect::<Vec<_>>();
+            assert_eq!(genes(&mutated), genes(&genome));
+
+            let activation = mutated.node_activations[&node];
+            assert_ne!(activation, current);
+            assert_eq!(mutated.node_activations.len(), 1);
+            (genome, current) = (mutated, activation);
+        }
+    }
+
+    #[test]
+    fn crossover_children_split_into_new_nodes() {
+        let trainer = trainer(|_| {});
+        let a = vec![gene(0, 50, 0.5, 0), gene(50, 3, 0.5, 1)];
+        let b = vec![gene(1, 70, 0.5, 2), gene(70, 4, 0.5, 3)];
+        let a = Genome::from_genes(trainer.clone(), a).unwrap();
+        let b = Genome::from_genes(trainer, b).unwrap();
+
+        for fitness in [(1.0, 0.0), (0.0, 1.0)] {
+            let mut child = a.crossover(&b, fitness);
+            let innovations = child.genes.iter().map(|x| x.innovation).collect::<Vec<_>>();
+            for innovation in innovations {
+                let nodes = |x: &Genome| {
+                    x.genes
+                        .iter()
+                        .flat_map(|x| [x.node_in, x.node_out])
+                        .collect::<BTreeSet<_>>()
+                };
+                let before = nodes(&child);
+                child.split_edge(innovation).unwrap();
+                let new = nodes(&child)
+                    .difference(&before)
+                    .copied()
+                    .collect::<Vec<_>>();
+                assert_eq!(new.len(), 1);
+                assert!(new[0] > 70);
+            }
+        }
+    }
+
+    #[test]
+    fn older_biased_prefers_older_edges() {
+        let splits = |selection| {
+            let trainer = trainer(|x| {
+                x.mutate_add_node = 1.0;
+                x.mutate_add_edge = 0.0;
+                x.add_node_selection = selection;
+            });
+            trainer.gen.store(9, Ordering::Release);
+            let mut new = gene(1, 3, 0.5, 1);
+            new.generation_added = 9;
+            let genome = Genome::from_genes(trainer, vec![gene(0, 3, 0.5, 0), new]).unwrap();
+
+            // Count how often the old edge (innovation 0) was split
+            (0..1000)
+                .filter(|_| !genome.mutate().genes[0].enabled)
+                .count()
         };
 
-        b_extra.retain(|x| x.innovation != i.innovation);
-        matching.push((i, matched));
+        // The old edge is weighted 10 to the new edge's 1
+        assert!(splits(AddNodeSelection::OlderBiased) > 800);
+        let uniform = splits(AddNodeSelection::Uniform);
+        assert!((400..600).contains(&uniform));
     }
 
-    (matching, a_extra, b_extra)
+    #[test]
+    fn crossover_blend_interpolates_weights() {
+        let trainer = trainer(|x| x.crossover_blend = 1.0);
+        let a = Genome::from_genes(trainer.clone(), vec![gene(0, 3, 0.2, 0)]).unwrap();
+        let b = Genome::from_genes(trainer, vec![gene(0, 3, 1.0, 0)]).unwrap();
+
+        // The weight is somewhere between the parents, the midpoint on average
+        let weights = (0..1000)
+            .map(|_| a.crossover(&b, (1.0, 0.0)).genes[0].weight)
+            .collect::<Vec<_>>();
+        assert!(weights.iter().all(|x| (0.2..=1.0).contains(x)));
+        let mean = weights.iter().sum::<f32>() / weights.len() as f32;
+        assert!((mean - 0.6).abs() < 0.05, "{mean}");
+    }
+
+    // Works out a node the way simulate did when node values were kept in a HashMap,
+    // `None` if no sensor reaches it
+    fn old_value(
+        network: &Network,
+        node: usize,
+        sensors: &[f32],
+        values: &mut HashMap<usize, Option<f32>>,
+    ) -> Option<f32> {
+        if let Some(value) = values.get(&node) {
+            return *value;
+        }
+        let node_type = network.classify_node(node);
+        if node_type == NodeType::Sensor {
+            return Some(sensors[node]);
+        }
+
+        let mut incoming = network
+            .genes
+            .iter()
+            .filter(|x| x.enabled && x.node_out == node)
+            .collect::<Vec<_>>();
+        incoming.sort_by_key(|x| x.innovation);
+        let mut sum = None;
+        for i in incoming {
+            if let Some(value) = old_value(network, i.node_in, sensors, values) {
+                sum = Some(sum.unwrap_or(0.0) + value * i.weight);
+            }
+        }
+
+        let activation = match network.node_activations.get(&node) {
+            Some(x) if node_type == NodeType::Hidden => *x,
+            _ => network.activations.get(node_type),
+        };
+        let value = sum.map(|x| activation.apply(x));
+        values.insert(node, value);
+        value
+    }
+
+    #[test]
+    fn simulate_matches_hashmap_values() {
+        let trainer = trainer(|x| {
+            x.mutate_add_node = 0.5;
+            x.mutate_disable_edge = 0.2;
+            x.mutate_activation = 0.3;
+        });
+        for genome in mutated_genomes(&trainer, 60) {
+            let network = genome.network();
+            for sensors in [[0.0, 0.0, 0.0], [1.0, 0.5, -0.5], [-3.0, 2.0, 0.25]] {
+                let mut values = HashMap::new();
+                let old = network
+                    .layout
+                    .output_range()
+                    .map(|x| {
+                        old_value(&network, x, &sensors, &mut values)
+                            .unwrap_or_else(|| network.activations.get(NodeType::Output).apply(0.0))
+                    })
+                    .collect::<Vec<_>>();
+                assert_eq!(genome.simulate(&sensors), old);
+            }
+        }
+    }
+
+    #[test]
+    fn near_zero_weights_are_disabled() {
+        let trainer = trainer(|x| {
+            x.mutate_prune_zero_weights = true;
+            x.mutate_weight = 0.0;
+            x.mutate_add_edge = 0.0;
+            x.mutate_add_node = 0.0;
+        });
+        let genes = vec![
+            gene(0, 3, 1e-4, 0),
+            gene(1, 3, -1e-4, 1),
+            gene(2, 3, 0.5, 2),
+        ];
+        let genome = Genome::from_genes(trainer, genes).unwrap().mutate();
+
+        let enabled = genome.genes.iter().map(|x| x.enabled).collect::<Vec<_>>();
+        assert_eq!(enabled, [false, false, true]);
+        assert_eq!(genome.simulate(&[1.0, 1.0, 1.0]), [0.5, 0.0]);
+    }
+
+    #[test]
+    fn loop_fed_output_matches_unconnected() {
+        let trainer = trainer(|x| x.output_activation = Activation::Sigmoid);
+        let mut genome = Genome::from_genes(trainer, vec![gene(0, 3, 0.5, 0)]).unwrap();
+        let unconnected = genome.simulate(&[1.0, 1.0, 1.0]);
+        assert_eq!(unconnected[1], 0.5);
+
+        // Output 4 is only fed by the loop 5 -> 6 -> 5, edited in by hand
+        for (i, (a, b)) in [(1, 5), (5, 6), (6, 5), (6, 4)].into_iter().enumerate() {
+            genome.genes.push(gene(a, b, 2.0, i + 1));
+        }
+        genome.invalidate_topology();
+        assert!(genome.is_recursive());
+        assert_eq!(genome.simulate(&[1.0, 1.0, 1.0]), unconnected);
+    }
+
+    #[cfg(feature = "ndarray")]
+    #[test]
+    fn simulate_ndarray_checks_sensor_count() {
+        let genome = Genome::new(trainer(|_| {}));
+        let inputs = ndarray::Array2::<f32>::zeros((4, 2));
+        assert_eq!(
+            genome.simulate_ndarray(inputs.view()),
+            Err(super::GenomeError::SensorCount)
+        );
+
+        let inputs = ndarray::Array2::<f32>::ones((4, 3));
+        let outputs = genome.simulate_ndarray(inputs.view()).unwrap();
+        for row in outputs.rows() {
+            assert_eq!(row.to_vec(), genome.simulate(&[1.0, 1.0, 1.0]));
+        }
+    }
+
+    #[test]
+    fn distance_is_symmetric() {
+        let trainer = trainer(|x| {
+            x.mutate_add_node = 0.5;
+            x.mutate_add_edge = 0.8;
+        });
+        let genomes = mutated_genomes(&trainer, 60);
+
+        for a in &genomes {
+            assert_eq!(a.distance(a), 0.0);
+            for b in &genomes {
+                assert_eq!(a.distance(b), b.distance(a));
+            }
+        }
+    }
 }
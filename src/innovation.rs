@@ -1,12 +1,16 @@
+#[cfg(feature = "std")]
 use std::sync::atomic::{AtomicUsize, Ordering};
 
+#[cfg(feature = "std")]
 use ahash::{HashMap, HashMapExt};
+#[cfg(feature = "std")]
 use parking_lot::Mutex;
 
 pub type EdgeCount = usize;
 pub type SpecieCount = usize;
 pub type GenomeCount = usize;
 
+#[cfg(feature = "std")]
 pub struct Innovations {
     // == Component Counts / IDs ==
     /// The id of a gene
@@ -15,22 +19,39 @@ pub struct Innovations {
     specie_count: AtomicUsize,
     /// The id of a genome
     genome_count: AtomicUsize,
+    /// The number of hidden nodes made by splitting edges
+    node_count: AtomicUsize,
 
     // == Edge record ==
     /// Maps (a, b) -> edge index
     past_connection: Mutex<HashMap<(usize, usize), usize>>,
+    /// Maps split edge -> (new hidden node, edge into new node, edge out of new node)
+    past_split: Mutex<HashMap<EdgeCount, (usize, EdgeCount, EdgeCount)>>,
 }
 
+#[cfg(feature = "std")]
 impl Innovations {
     pub fn new() -> Self {
         Self {
             edge_count: AtomicUsize::new(0),
             specie_count: AtomicUsize::new(0),
             genome_count: AtomicUsize::new(0),
+            node_count: AtomicUsize::new(0),
             past_connection: Mutex::new(HashMap::new()),
+            past_split: Mutex::new(HashMap::new()),
         }
     }
 
+    /// Zeros all counters and forgets every past connection and split
+    pub fn reset(&self) {
+        self.edge_count.store(0, Ordering::Release);
+        self.specie_count.store(0, Ordering::Release);
+        self.genome_count.store(0, Ordering::Release);
+        self.node_count.store(0, Ordering::Release);
+        self.past_connection.lock().clear();
+        self.past_split.lock().clear();
+    }
+
     // == New Innovations ==
     pub fn new_edge(&self, x: (usize, usize)) -> EdgeCount {
         *self
@@ -40,6 +61,41 @@ impl Innovations {
             .or_insert_with(|| self.edge_count.fetch_add(1, Ordering::AcqRel))
     }
 
+    /// Gets the id of the new hidden node and the innovations of the two edges made when splitting an edge.
+    /// The same split edge always gives the same node and edges in every genome, so they line up in crossover.
+    /// Hidden node ids are handed out from `first_hidden` up, which should be `NodeLayout::hidden_start`.
+    pub fn new_node_innovation(
+        &self,
+        split_edge: EdgeCount,
+        first_hidden: usize,
+    ) -> (usize, EdgeCount, EdgeCount) {
+        *self.past_split.lock().entry(split_edge).or_insert_with(|| {
+            (
+                first_hidden + self.node_count.fetch_add(1, Ordering::AcqRel),
+                self.edge_count.fetch_add(1, Ordering::AcqRel),
+                self.edge_count.fetch_add(1, Ordering::AcqRel),
+            )
+        })
+    }
+
+    /// Records that the connection `x` has this innovation, unless it already has one.
+    /// Used for genes that came from somewhere else, so making the same connection again gives the same innovation.
+    pub fn register_edge(&self, x: (usize, usize), innovation: EdgeCount) {
+        self.past_connection.lock().entry(x).or_insert(innovation);
+    }
+
+    /// Makes sure edge innovations handed out from now on are at least `count`,
+    /// so they don't clash with innovations that came from somewhere else
+    pub fn reserve_edges(&self, count: EdgeCount) {
+        self.edge_count.fetch_max(count, Ordering::AcqRel);
+    }
+
+    /// Makes sure hidden nodes made from now on come after the first `count` hidden nodes,
+    /// so they don't clash with nodes that came from somewhere else
+    pub fn reserve_nodes(&self, count: usize) {
+        self.node_count.fetch_max(count, Ordering::AcqRel);
+    }
+
     pub fn new_specie(&self) -> SpecieCount {
         self.specie_count.fetch_add(1, Ordering::AcqRel)
     }
@@ -47,4 +103,37 @@ impl Innovations {
     pub fn new_genome(&self) -> GenomeCount {
         self.genome_count.fetch_add(1, Ordering::AcqRel)
     }
+
+    // == Stats ==
+    /// Gets the number of edge innovations handed out so far
+    pub fn edge_count(&self) -> EdgeCount {
+        self.edge_count.load(Ordering::Acquire)
+    }
+
+    /// Gets the number of species made so far
+    pub fn specie_count(&self) -> SpecieCount {
+        self.specie_count.load(Ordering::Acquire)
+    }
+
+    /// Gets the number of genomes made so far
+    pub fn genome_count(&self) -> GenomeCount {
+        self.genome_count.load(Ordering::Acquire)
+    }
+
+    /// Gets the number of hidden nodes made by splitting edges so far
+    pub fn node_count(&self) -> usize {
+        self.node_count.load(Ordering::Acquire)
+    }
+
+    /// Gets the number of distinct (a, b) connections that have been made
+    pub fn distinct_connections(&self) -> usize {
+        self.past_connection.lock().len()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for Innovations {
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -0,0 +1,23 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "benchmarks")]
+pub mod benchmarks;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod genome;
+pub mod innovation;
+pub mod misc;
+pub mod network;
+#[cfg(feature = "std")]
+pub mod novelty;
+#[cfg(feature = "std")]
+pub mod pareto;
+#[cfg(feature = "std")]
+pub mod species;
+#[cfg(test)]
+mod test_util;
+#[cfg(feature = "std")]
+pub mod trainer;
@@ -1,42 +1,44 @@
 use std::sync::Arc;
 
-mod config;
-mod genome;
-mod innovation;
-mod misc;
-mod species;
-mod trainer;
-use genome::Genome;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use neat::{
+    genome::Genome,
+    misc::sigmoid,
+    trainer::{StopCondition, Trainer},
+};
 
-use crate::{misc::sigmoid, trainer::Trainer};
+// Prints generation summaries to stdout
+struct Logger;
 
-fn main() {
-    // Create a new trainer with 2 inputs and 1 output
-    // Then populate it
-    let trainer = Arc::new(Trainer::new(3, 1)).populate();
-    let mut best = None;
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info
+    }
 
-    // Evolve for 200 genarations
-    for _ in 1..=30 {
-        trainer.gen(fit);
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            println!("{}", record.args());
+        }
     }
 
-    let fitness = trainer.species_fitness(&trainer.fitness(fit));
-    let maxfit = fitness.iter().fold(f32::MIN, |x, i| x.max(*i));
-    best = Some(
-        trainer.agents.read()[fitness
-            .iter()
-            .enumerate()
-            .find(|x| *x.1 == maxfit)
-            .unwrap()
-            .0]
-            .clone(),
-    );
+    fn flush(&self) {}
+}
+
+fn main() {
+    log::set_logger(&Logger).unwrap();
+    log::set_max_level(LevelFilter::Info);
+
+    // Create a new trainer with 2 inputs (and a bias) and 1 output
+    // Then populate it
+    let trainer = Arc::new(Trainer::new(3, 1).with_bias(0)).populate();
+
+    // Evolve for 30 genarations
+    let (best, _) = trainer.run(fit, StopCondition::Generations(30)).unwrap();
 
     for i in [[false, false], [false, true], [true, false], [true, true]] {
         let inp = [1.0, i[0] as usize as f32, i[1] as usize as f32];
         let real = (i[0] ^ i[1]) as usize as f32;
-        let got = sigmoid(best.as_ref().unwrap().simulate(&inp)[0]);
+        let got = sigmoid(best.simulate(&inp)[0]);
         println!(
             "{:5?} | REAL {} | GOT {:.3} | {}",
             i,
@@ -46,7 +48,7 @@ fn main() {
         );
     }
 
-    println!("{}", best.unwrap().debug());
+    println!("{}", best.to_mermaid());
 }
 
 // Define an XoR fitness function
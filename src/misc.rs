@@ -1,3 +1,12 @@
+use core::cmp::Ordering;
+
+use num_traits::Float;
+
+use alloc::{
+    format,
+    string::{String, ToString},
+};
+
 pub trait SignString {
     fn sign_str(&self) -> String;
 }
@@ -11,6 +20,20 @@ impl SignString for f32 {
     }
 }
 
-pub fn sigmoid(inp: f32) -> f32 {
-    1.0 / (1.0 + (/*-4.9 */-1.0 * inp).exp())
+/// Compares two fitnesses, NaN is always the worst
+pub fn fitness_cmp(a: f32, b: f32) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        (false, false) => a.total_cmp(&b),
+    }
+}
+
+pub fn sigmoid<T: Float>(inp: T) -> T {
+    T::one() / (T::one() + (/*-4.9 */-inp).exp())
+}
+
+pub fn tanh<T: Float>(inp: T) -> T {
+    inp.tanh()
 }
@@ -0,0 +1,676 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{fmt::Display, ops::Range};
+
+use num_traits::Float;
+
+use crate::{
+    innovation::EdgeCount,
+    misc::{sigmoid, tanh},
+};
+
+/// An edge between two nodes.
+/// The weight is `f32` while training, but networks can be converted to any [`Float`] to be run (see [`Network::cast`]).
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Gene<T = f32> {
+    pub node_in: usize,
+    pub node_out: usize,
+    pub weight: T,
+    pub enabled: bool,
+    pub innovation: EdgeCount,
+    /// The generation the gene was made in, it is kept through crossover
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub generation_added: usize,
+}
+
+impl<T: Float> Gene<T> {
+    /// Converts the weight to another float type
+    pub fn cast<U: Float>(&self) -> Gene<U> {
+        Gene {
+            node_in: self.node_in,
+            node_out: self.node_out,
+            weight: U::from(self.weight).unwrap_or_else(U::nan),
+            enabled: self.enabled,
+            innovation: self.innovation,
+            generation_added: self.generation_added,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeType {
+    Sensor,
+    Output,
+    Hidden,
+}
+
+impl NodeType {
+    /// The lowercase name of the node type, as used in [`Network::to_inference_json`]
+    pub fn name(&self) -> &'static str {
+        match self {
+            NodeType::Sensor => "sensor",
+            NodeType::Output => "output",
+            NodeType::Hidden => "hidden",
+        }
+    }
+}
+
+/// The function applied to the summed input of a node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Activation {
+    Sigmoid,
+    Tanh,
+    Relu,
+    /// Passes the value through unchanged
+    Linear,
+}
+
+impl Activation {
+    /// Every activation, in the order they are listed
+    pub const ALL: [Activation; 4] = [
+        Activation::Sigmoid,
+        Activation::Tanh,
+        Activation::Relu,
+        Activation::Linear,
+    ];
+
+    /// The lowercase name of the activation, as used in [`Network::to_inference_json`]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Activation::Sigmoid => "sigmoid",
+            Activation::Tanh => "tanh",
+            Activation::Relu => "relu",
+            Activation::Linear => "linear",
+        }
+    }
+
+    pub fn apply<T: Float>(&self, inp: T) -> T {
+        match self {
+            Activation::Sigmoid => sigmoid(inp),
+            Activation::Tanh => tanh(inp),
+            Activation::Relu => inp.max(T::zero()),
+            Activation::Linear => inp,
+        }
+    }
+}
+
+/// The activation used by each type of node.
+/// Sensors never have an activation, they just hold their input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Activations {
+    pub hidden: Activation,
+    pub output: Activation,
+    /// Applied to all the outputs together, after `output`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub output_mode: OutputMode,
+}
+
+impl Activations {
+    pub fn get(&self, node_type: NodeType) -> Activation {
+        match node_type {
+            NodeType::Sensor => Activation::Linear,
+            NodeType::Output => self.output,
+            NodeType::Hidden => self.hidden,
+        }
+    }
+}
+
+impl Default for Activations {
+    fn default() -> Self {
+        Self {
+            hidden: Activation::Sigmoid,
+            output: Activation::Linear,
+            output_mode: OutputMode::Independent,
+        }
+    }
+}
+
+/// How the output values are put together after each output node's activation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OutputMode {
+    /// Each output is left as is
+    #[default]
+    Independent,
+    /// The outputs are put through a softmax, so they are all positive and add up to 1
+    Softmax,
+}
+
+impl OutputMode {
+    /// The lowercase name of the output mode, as used in [`Network::to_inference_json`]
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputMode::Independent => "independent",
+            OutputMode::Softmax => "softmax",
+        }
+    }
+
+    pub fn apply<T: Float>(&self, outputs: &mut [T]) {
+        match self {
+            OutputMode::Independent => {}
+            OutputMode::Softmax => {
+                // Shifting by the max keeps exp from overflowing and doesn't change the result
+                let max = outputs.iter().copied().fold(T::neg_infinity(), T::max);
+                outputs.iter_mut().for_each(|x| *x = (*x - max).exp());
+                let sum = outputs.iter().copied().fold(T::zero(), |a, b| a + b);
+                outputs.iter_mut().for_each(|x| *x = *x / sum);
+            }
+        }
+    }
+}
+
+/// Where each type of node is in the node ids.
+/// Sensors come first, then outputs, then every hidden node.
+/// All node type logic should go through here.
+///
+/// When simulating, sensors are given the inputs in id order.
+/// The last `constants` sensors are for values the caller controls that aren't really inputs
+/// (like a time step), they come after the normal inputs and are counted in `inputs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NodeLayout {
+    /// The number of sensor nodes
+    pub inputs: usize,
+    /// The number of output nodes
+    pub outputs: usize,
+    /// A sensor that is always given 1.0 to act as a bias, if any
+    pub bias: Option<usize>,
+    /// The number of sensors at the end that are given constant values
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub constants: usize,
+}
+
+impl NodeLayout {
+    pub fn new(inputs: usize, outputs: usize) -> Self {
+        Self {
+            inputs,
+            outputs,
+            bias: None,
+            constants: 0,
+        }
+    }
+
+    /// The ids of the sensor nodes
+    pub fn sensor_range(&self) -> Range<usize> {
+        0..self.inputs
+    }
+
+    /// The ids of the output nodes
+    pub fn output_range(&self) -> Range<usize> {
+        self.inputs..self.inputs + self.outputs
+    }
+
+    /// The id of the first hidden node, every id from here on is hidden
+    pub fn hidden_start(&self) -> usize {
+        self.inputs + self.outputs
+    }
+
+    /// The ids of the constant sensors
+    pub fn constant_range(&self) -> Range<usize> {
+        self.inputs - self.constants..self.inputs
+    }
+
+    pub fn is_bias(&self, id: usize) -> bool {
+        self.bias == Some(id)
+    }
+
+    pub fn is_constant(&self, id: usize) -> bool {
+        self.constant_range().contains(&id)
+    }
+
+    pub fn classify(&self, id: usize) -> NodeType {
+        if id < self.inputs {
+            return NodeType::Sensor;
+        }
+
+        if id < self.hidden_start() {
+            return NodeType::Output;
+        }
+
+        NodeType::Hidden
+    }
+}
+
+/// Just the parts of a genome needed to run it.
+/// Unlike `Genome` this does not need `std` so evolved networks can be run anywhere with `alloc`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(deserialize = "T: Float + serde::Deserialize<'de>"))
+)]
+pub struct Network<T = f32> {
+    pub layout: NodeLayout,
+    pub genes: Vec<Gene<T>>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub activations: Activations,
+    /// Activations of single hidden nodes that differ from `activations.hidden`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub node_activations: BTreeMap<usize, Activation>,
+    /// Node values are clamped to plus or minus this so infinities stay finite, NaNs become 0
+    #[cfg_attr(feature = "serde", serde(default = "default_value_limit"))]
+    pub value_limit: T,
+}
+
+#[cfg(feature = "serde")]
+fn default_value_limit<T: Float>() -> T {
+    T::max_value()
+}
+
+struct NodeTester<'a, T> {
+    ctx: &'a mut SimulateCtx<T>,
+    genes: &'a [Gene<T>],
+    layout: NodeLayout,
+    activations: Activations,
+    node_activations: &'a BTreeMap<usize, Activation>,
+    value_limit: T,
+    stats: SimulateStats,
+}
+
+/// Buffers used while running a network.
+/// Keeping one around and passing it to `Genome::simulate_with` saves allocating them on every run.
+#[derive(Debug, Clone)]
+pub struct SimulateCtx<T = f32> {
+    // The value of every node by id, `None` if it hasn't been worked out
+    values: Vec<Option<T>>,
+    // Indices of the genes going into the node being worked out
+    incoming: Vec<usize>,
+    pub(crate) outputs: Vec<T>,
+}
+
+impl<T> SimulateCtx<T> {
+    pub fn new() -> Self {
+        Self {
+            values: Vec::new(),
+            incoming: Vec::new(),
+            outputs: Vec::new(),
+        }
+    }
+}
+
+impl<T> Default for SimulateCtx<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How much work a simulation took, see `Genome::simulate_profiled`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimulateStats {
+    /// The number of (non sensor) nodes worked out
+    pub nodes: usize,
+    /// The number of enabled edges followed
+    pub edges: usize,
+}
+
+impl<T: Float> Network<T> {
+    pub fn classify_node(&self, id: usize) -> NodeType {
+        self.layout.classify(id)
+    }
+
+    /// Runs the network, see [`NodeLayout`] for the order sensors take their values in.
+    /// An output with no enabled edges into it (or only edges from nodes no sensor reaches) always gives the output activation of 0,
+    /// like 0.5 with a sigmoid. Outputs fed by a loop can't be ordered (along with everything else downstream of the loop),
+    /// so they also give the output activation of 0, even if they have edges from sensors too
+    pub fn simulate(&self, sensors: &[T]) -> Vec<T> {
+        simulate(
+            &self.genes,
+            self.layout,
+            self.activations,
+            &self.node_activations,
+            self.value_limit,
+            sensors,
+        )
+    }
+
+    pub fn is_recursive(&self) -> bool {
+        is_recursive(&self.genes, self.layout)
+    }
+
+    /// Converts the network to another float type, to run it with more (or less) precision
+    pub fn cast<U: Float>(&self) -> Network<U> {
+        Network {
+            layout: self.layout,
+            genes: self.genes.iter().map(|x| x.cast()).collect(),
+            activations: self.activations,
+            node_activations: self.node_activations.clone(),
+            value_limit: U::from(self.value_limit).unwrap_or_else(U::max_value),
+        }
+    }
+
+    /// Gets rid of genes that can't change the output, see `Genome::prune`
+    pub fn prune(&mut self, drop_disabled: bool) {
+        prune(&mut self.genes, self.layout, drop_disabled);
+    }
+}
+
+impl<T: Float + Display> Network<T> {
+    /// Writes the network in a small JSON format that can be evaluated outside of Rust.
+    ///
+    /// ```json
+    /// {
+    ///   "inputs": 2,
+    ///   "outputs": 1,
+    ///   "bias": null,
+    ///   "constants": 0,
+    ///   "output_mode": "independent",
+    ///   "nodes": [{ "id": 0, "type": "sensor", "activation": "linear" }, ...],
+    ///   "edges": [{ "from": 0, "to": 2, "weight": 0.5, "enabled": true }, ...]
+    /// }
+    /// ```
+    ///
+    /// Sensors take the inputs in order, the bias sensor (if any) should always be given 1.0.
+    /// The last `constants` sensors are constant values the caller controls.
+    /// Every other node is its activation applied to the sum of `value(from) * weight` over its enabled incoming edges,
+    /// where nodes no sensor reaches count as 0.
+    /// Nodes in a loop and every node downstream of one are skipped entirely,
+    /// an output that is skipped (even one that also has edges from sensors) gives its activation of 0.
+    /// Outputs are read off in id order, then put through a softmax together if `output_mode` is `"softmax"`.
+    /// Non finite weights are written as `null`.
+    pub fn to_inference_json(&self) -> String {
+        let mut nodes = (0..self.layout.hidden_start()).collect::<BTreeSet<_>>();
+        nodes.extend(self.genes.iter().flat_map(|x| [x.node_in, x.node_out]));
+        let nodes = nodes
+            .into_iter()
+            .map(|x| {
+                let node_type = self.layout.classify(x);
+                format!(
+                    r#"{{"id":{},"type":"{}","activation":"{}"}}"#,
+                    x,
+                    node_type.name(),
+                    node_activation(self.activations, &self.node_activations, node_type, x).name()
+                )
+            })
+            .collect::<Vec<_>>();
+        let edges = self
+            .genes
+            .iter()
+            .map(|x| {
+                let weight = if x.weight.is_finite() {
+                    x.weight.to_string()
+                } else {
+                    "null".to_string()
+                };
+                format!(
+                    r#"{{"from":{},"to":{},"weight":{},"enabled":{}}}"#,
+                    x.node_in, x.node_out, weight, x.enabled
+                )
+            })
+            .collect::<Vec<_>>();
+        let bias = match self.layout.bias {
+            Some(i) => i.to_string(),
+            None => "null".to_string(),
+        };
+
+        format!(
+            r#"{{"inputs":{},"outputs":{},"bias":{},"constants":{},"output_mode":"{}","nodes":[{}],"edges":[{}]}}"#,
+            self.layout.inputs,
+            self.layout.outputs,
+            bias,
+            self.layout.constants,
+            self.activations.output_mode.name(),
+            nodes.join(","),
+            edges.join(",")
+        )
+    }
+}
+
+pub(crate) fn simulate<T: Float>(
+    genes: &[Gene<T>],
+    layout: NodeLayout,
+    activations: Activations,
+    node_activations: &BTreeMap<usize, Activation>,
+    value_limit: T,
+    sensors: &[T],
+) -> Vec<T> {
+    let order = topological_order(genes, layout);
+    let mut ctx = SimulateCtx::new();
+    simulate_ordered(
+        &mut ctx,
+        genes,
+        &order,
+        layout,
+        activations,
+        node_activations,
+        value_limit,
+        sensors,
+    );
+    ctx.outputs
+}
+
+/// Runs the network using an already worked out [`topological_order`], leaving the outputs in `ctx.outputs`.
+/// Returns how much work it took
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn simulate_ordered<T: Float>(
+    ctx: &mut SimulateCtx<T>,
+    genes: &[Gene<T>],
+    order: &[usize],
+    layout: NodeLayout,
+    activations: Activations,
+    node_activations: &BTreeMap<usize, Activation>,
+    value_limit: T,
+    sensors: &[T],
+) -> SimulateStats {
+    debug_assert_eq!(sensors.len(), layout.inputs);
+    let nodes = genes
+        .iter()
+        .map(|x| x.node_in.max(x.node_out) + 1)
+        .fold(layout.hidden_start(), usize::max);
+    ctx.values.clear();
+    ctx.values.resize(nodes, None);
+    for (i, &value) in layout.sensor_range().zip(sensors) {
+        ctx.values[i] = Some(value);
+    }
+
+    let mut node_tester = NodeTester {
+        ctx,
+        genes,
+        layout,
+        activations,
+        node_activations,
+        value_limit,
+        stats: SimulateStats::default(),
+    };
+    node_tester.prop(order);
+    let (ctx, stats) = (node_tester.ctx, node_tester.stats);
+
+    // Outputs are always in the order unless a loop feeds into them,
+    // those are treated like an output with no edges into it so every output is activated the same way
+    ctx.outputs.clear();
+    ctx.outputs.extend(layout.output_range().map(|x| {
+        ctx.values[x].unwrap_or_else(|| {
+            node_activation(activations, node_activations, NodeType::Output, x).apply(T::zero())
+        })
+    }));
+    activations.output_mode.apply(&mut ctx.outputs);
+    stats
+}
+
+/// Orders the nodes so each one comes after every node with an enabled edge into it.
+/// Nodes that are part of a loop can't be ordered and are left out,
+/// as are nodes that aren't on a path from a sensor to an output (see [`reachable_nodes`]).
+/// Outputs are always kept so they still get a value.
+pub(crate) fn topological_order<T>(genes: &[Gene<T>], layout: NodeLayout) -> Vec<usize> {
+    let reachable = reachable_nodes(genes, layout);
+    sort_nodes(genes, &node_set(genes, layout))
+        .into_iter()
+        .filter(|x| reachable.contains(x) || layout.classify(*x) == NodeType::Output)
+        .collect()
+}
+
+/// Orders every sensor, output and connected node so each one comes after every node with an enabled edge into it.
+/// Unlike [`topological_order`] nodes that can't reach an output are kept, only nodes in a loop are left out.
+#[cfg(feature = "std")]
+pub(crate) fn full_order<T>(genes: &[Gene<T>], layout: NodeLayout) -> Vec<usize> {
+    sort_nodes(genes, &node_set(genes, layout))
+}
+
+/// Checks if the enabled genes make a loop anywhere in the network
+pub(crate) fn is_recursive<T>(genes: &[Gene<T>], layout: NodeLayout) -> bool {
+    let nodes = node_set(genes, layout);
+    sort_nodes(genes, &nodes).len() < nodes.len()
+}
+
+/// Checks if adding an enabled edge from `a` to `b` would make a loop, assuming there isn't one already.
+/// This is the case if `b` can already get to `a`.
+#[cfg(feature = "std")]
+pub(crate) fn would_loop<T>(genes: &[Gene<T>], a: usize, b: usize) -> bool {
+    a == b || walk(genes, core::iter::once(b), |x| (x.node_in, x.node_out)).contains(&a)
+}
+
+/// The activation of a node, hidden nodes use their entry in `node_activations` if they have one
+fn node_activation(
+    activations: Activations,
+    node_activations: &BTreeMap<usize, Activation>,
+    node_type: NodeType,
+    id: usize,
+) -> Activation {
+    match node_activations.get(&id) {
+        Some(i) if node_type == NodeType::Hidden => *i,
+        _ => activations.get(node_type),
+    }
+}
+
+/// Disables genes going into a node with no enabled path to an output.
+/// If `drop_disabled` is set those genes are removed instead, along with every other disabled gene
+pub(crate) fn prune<T>(genes: &mut Vec<Gene<T>>, layout: NodeLayout, drop_disabled: bool) {
+    let live = live_nodes(genes, layout);
+    if drop_disabled {
+        genes.retain(|x| x.enabled && live.contains(&x.node_out));
+        return;
+    }
+
+    for i in genes.iter_mut().filter(|x| !live.contains(&x.node_out)) {
+        i.enabled = false;
+    }
+}
+
+/// Every node with an enabled path to an output, including the outputs themselves
+pub(crate) fn live_nodes<T>(genes: &[Gene<T>], layout: NodeLayout) -> BTreeSet<usize> {
+    walk(genes, layout.output_range(), |x| (x.node_out, x.node_in))
+}
+
+/// Every node on some enabled path from a sensor to an output.
+/// Nodes outside of this can't affect the outputs (other than by acting as a 0).
+pub(crate) fn reachable_nodes<T>(genes: &[Gene<T>], layout: NodeLayout) -> BTreeSet<usize> {
+    let from_sensors = walk(genes, layout.sensor_range(), |x| (x.node_in, x.node_out));
+    live_nodes(genes, layout)
+        .intersection(&from_sensors)
+        .copied()
+        .collect()
+}
+
+// Finds every node that can be gotten to from `start` over enabled genes.
+// `step` gives the (from, to) of a gene, so walking backwards is just swapping them.
+fn walk<T>(
+    genes: &[Gene<T>],
+    start: impl Iterator<Item = usize>,
+    step: impl Fn(&Gene<T>) -> (usize, usize),
+) -> BTreeSet<usize> {
+    let mut seen = start.collect::<BTreeSet<_>>();
+    let mut todo = seen.iter().copied().collect::<Vec<_>>();
+
+    while let Some(node) = todo.pop() {
+        for (_, to) in genes
+            .iter()
+            .filter(|x| x.enabled)
+            .map(&step)
+            .filter(|x| x.0 == node)
+        {
+            if seen.insert(to) {
+                todo.push(to);
+            }
+        }
+    }
+
+    seen
+}
+
+// Every sensor and output along with any node an enabled gene connects
+fn node_set<T>(genes: &[Gene<T>], layout: NodeLayout) -> BTreeSet<usize> {
+    let mut nodes = (0..layout.hidden_start()).collect::<BTreeSet<_>>();
+    for i in genes.iter().filter(|x| x.enabled) {
+        nodes.insert(i.node_in);
+        nodes.insert(i.node_out);
+    }
+
+    nodes
+}
+
+// Kahn's algorithm, nodes in a loop always have an edge in left so they never get added
+fn sort_nodes<T>(genes: &[Gene<T>], nodes: &BTreeSet<usize>) -> Vec<usize> {
+    let mut incoming = BTreeMap::<usize, usize>::new();
+    let mut outgoing = BTreeMap::<usize, Vec<usize>>::new();
+    for i in genes.iter().filter(|x| x.enabled) {
+        *incoming.entry(i.node_out).or_default() += 1;
+        outgoing.entry(i.node_in).or_default().push(i.node_out);
+    }
+
+    let mut ready = nodes
+        .iter()
+        .filter(|x| !incoming.contains_key(x))
+        .copied()
+        .collect::<Vec<_>>();
+    let mut out = Vec::with_capacity(nodes.len());
+
+    while let Some(node) = ready.pop() {
+        out.push(node);
+
+        for i in outgoing.get(&node).into_iter().flatten() {
+            let count = incoming.get_mut(i).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.push(*i);
+            }
+        }
+    }
+
+    out
+}
+
+impl<T: Float> NodeTester<'_, T> {
+    // Works out the value of every node in topological order,
+    // so the nodes going into one are always done before it
+    fn prop(&mut self, order: &[usize]) {
+        for &to in order {
+            let node_type = self.layout.classify(to);
+            if node_type == NodeType::Sensor {
+                continue;
+            }
+
+            // Get nodes that connect to this one
+            // These are summed in innovation order so the same genes always give the same output,
+            // no matter what order they are stored in
+            let genes = self.genes;
+            let incoming = &mut self.ctx.incoming;
+            incoming.clear();
+            incoming
+                .extend((0..genes.len()).filter(|&x| genes[x].enabled && genes[x].node_out == to));
+            incoming.sort_by_key(|&x| genes[x].innovation);
+
+            // Nodes without a value (part of a loop) count as 0
+            self.stats.nodes += 1;
+            self.stats.edges += incoming.len();
+            let mut out = T::zero();
+            for &i in incoming.iter() {
+                let value = self.ctx.values[genes[i].node_in].unwrap_or_else(T::zero);
+                out = out + value * genes[i].weight;
+            }
+
+            // Big weights can overflow, which would otherwise spread through the rest of the network
+            let mut out =
+                node_activation(self.activations, self.node_activations, node_type, to).apply(out);
+            if out.is_nan() {
+                out = T::zero();
+            }
+            self.ctx.values[to] = Some(out.max(-self.value_limit).min(self.value_limit));
+        }
+    }
+}
@@ -0,0 +1,24 @@
+// Novelty search scoring for `Trainer::gen_novelty`
+
+/// Gets the euclidean distance between two behaviors
+pub fn distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// Gets the average distance from `behavior` to its `k` nearest neighbors in `others`.
+/// If there are fewer than `k` others all of them are used.
+pub fn novelty<'a>(behavior: &[f32], others: impl Iterator<Item = &'a Vec<f32>>, k: usize) -> f32 {
+    let mut distances = others.map(|x| distance(behavior, x)).collect::<Vec<_>>();
+    distances.sort_by(|a, b| a.total_cmp(b));
+    distances.truncate(k);
+
+    if distances.is_empty() {
+        return 0.0;
+    }
+
+    distances.iter().sum::<f32>() / distances.len() as f32
+}
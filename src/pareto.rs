@@ -0,0 +1,99 @@
+// Multi objective ranking for `Trainer::gen_multi`, following NSGA-II
+// Every objective is maximized, like normal fitness
+
+/// Checks if `a` is at least as good as `b` in every objective and better in at least one
+pub fn dominates(a: &[f32], b: &[f32]) -> bool {
+    a.iter().zip(b).all(|(a, b)| a >= b) && a.iter().zip(b).any(|(a, b)| a > b)
+}
+
+/// Splits the genomes into Pareto fronts.
+/// The first front is every genome no other one dominates, the second is every genome only dominated by
+/// the first front and so on.
+pub fn non_dominated_sort(objectives: &[Vec<f32>]) -> Vec<Vec<usize>> {
+    let len = objectives.len();
+    let mut dominated_count = vec![0; len];
+    let mut dominates_list = vec![Vec::new(); len];
+
+    for i in 0..len {
+        for j in 0..len {
+            if i != j && dominates(&objectives[i], &objectives[j]) {
+                dominates_list[i].push(j);
+                dominated_count[j] += 1;
+            }
+        }
+    }
+
+    let mut fronts = Vec::new();
+    let mut front = (0..len)
+        .filter(|&x| dominated_count[x] == 0)
+        .collect::<Vec<_>>();
+
+    while !front.is_empty() {
+        let mut next = Vec::new();
+        for &i in &front {
+            for &j in &dominates_list[i] {
+                dominated_count[j] -= 1;
+                if dominated_count[j] == 0 {
+                    next.push(j);
+                }
+            }
+        }
+
+        fronts.push(front);
+        front = next;
+    }
+
+    fronts
+}
+
+/// Gets how far each genome in the front is from its neighbors in objective space.
+/// Genomes at the ends of the front (for any objective) get an infinite distance.
+pub fn crowding_distance(objectives: &[Vec<f32>], front: &[usize]) -> Vec<f32> {
+    let mut distance = vec![0.0; front.len()];
+    if front.is_empty() {
+        return distance;
+    }
+
+    for k in 0..objectives[front[0]].len() {
+        let value = |x: usize| objectives[front[x]].get(k).copied().unwrap_or(0.0);
+        let mut order = (0..front.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| value(a).total_cmp(&value(b)));
+
+        let first = order[0];
+        let last = order[order.len() - 1];
+        distance[first] = f32::INFINITY;
+        distance[last] = f32::INFINITY;
+
+        let range = value(last) - value(first);
+        if range == 0.0 {
+            continue;
+        }
+
+        for i in 1..order.len().saturating_sub(1) {
+            distance[order[i]] += (value(order[i + 1]) - value(order[i - 1])) / range;
+        }
+    }
+
+    distance
+}
+
+/// Turns the fronts into one fitness value per genome to use in the normal fitness pipeline.
+/// Earlier fronts always score higher, and within a front less crowded genomes score higher.
+/// The fitness is `(number of fronts - front index) + [0, 1) from the crowding distance` so it is always positive.
+pub fn fitness(objectives: &[Vec<f32>], fronts: &[Vec<usize>]) -> Vec<f32> {
+    let mut out = vec![0.0; objectives.len()];
+
+    for (i, front) in fronts.iter().enumerate() {
+        let rank = (fronts.len() - i) as f32;
+        for (&genome, distance) in front.iter().zip(crowding_distance(objectives, front)) {
+            let crowding = if distance.is_infinite() {
+                1.0
+            } else {
+                distance / (1.0 + distance)
+            };
+            out[genome] = rank + crowding;
+        }
+    }
+
+    out
+}
@@ -8,15 +8,32 @@ pub struct Specie {
 
     /// The genome that classifies the spesies
     pub owner: Genome,
-    /// The number of agents in the species
+    /// The number of agents in the species, updated by `Trainer::species_categorize`
     pub count: usize,
     /// The genaration at which the spesies was created
-    _age: usize,
+    created: usize,
     /// The last fitness of the spesies
     fitness: Option<f32>,
     /// The number of genarations the fitness hasent gone up
     /// If it goes up this should be reset
     stagnant: usize,
+    /// What the add node / edge chances of the species members are multiplied by when mutating
+    mutation_scale: f32,
+}
+
+/// A snapshot of a species, see [`Specie::stats`]
+#[derive(Debug, Clone, Copy)]
+pub struct SpeciesStats {
+    /// Id of the species
+    pub id: SpecieCount,
+    /// The number of agents in the species
+    pub count: usize,
+    /// The mean fitness of the species
+    pub fitness: Option<f32>,
+    /// The number of generations since the species was created
+    pub age: usize,
+    /// The number of generations the fitness hasn't gone up
+    pub stagnant: usize,
 }
 
 impl Specie {
@@ -28,23 +45,70 @@ impl Specie {
             id,
             Self {
                 id,
-                _age: owner.trainer.gen.load(Ordering::Acquire),
+                created: owner.trainer.gen.load(Ordering::Acquire),
                 owner,
                 count: 0,
                 fitness: None,
                 stagnant: 0,
+                mutation_scale: 1.0,
             },
         )
     }
 
+    /// Gets the number of generations since the species was created
+    pub fn age(&self) -> usize {
+        self.owner.trainer.gen.load(Ordering::Acquire) - self.created
+    }
+
+    /// Gets the mean fitness of the species as of the last `update_fitness`
+    pub fn fitness(&self) -> Option<f32> {
+        self.fitness
+    }
+
+    /// Gets the number of generations the fitness hasn't gone up
+    pub fn stagnant(&self) -> usize {
+        self.stagnant
+    }
+
+    /// Gets what the add node / edge chances of the species members are multiplied by when mutating.
+    /// This is always 1 unless `adaptive_mutation` is enabled, then it goes up with the stagnation count.
+    pub fn mutation_scale(&self) -> f32 {
+        self.mutation_scale
+    }
+
+    /// Gets a copy of the species stats
+    pub fn stats(&self) -> SpeciesStats {
+        SpeciesStats {
+            id: self.id,
+            count: self.count,
+            fitness: self.fitness,
+            age: self.age(),
+            stagnant: self.stagnant,
+        }
+    }
+
     /// Kill a set percent of the population
+    /// The trainer's best genome (the one with the highest raw fitness last generation) is never killed.
+    /// This locks the trainers agents, so it can't be called while they are locked
     pub fn kill(&self) {
         let mut species = self.this_species();
+        // Always leave at least one member so the species lives on
         let to_remove =
-            (species.len() as f32 * self.owner.trainer.config.population_kill_percent) as usize;
-        species.sort_by(|a, b| a.fitness.unwrap().partial_cmp(&b.fitness.unwrap()).unwrap());
+            ((species.len() as f32 * self.owner.trainer.config().population_kill_percent) as usize)
+                .min(species.len().saturating_sub(1));
+        // NaN fitness sorts first (as do genomes that haven't been evaluated),
+        // so those genomes are killed before any others
+        let tie_break = self.owner.trainer.config().tie_break;
+        species.sort_by(|a, b| {
+            tie_break.compare(
+                (a, a.fitness.unwrap_or(f32::NAN)),
+                (b, b.fitness.unwrap_or(f32::NAN)),
+            )
+        });
+        let best = self.owner.trainer.best_id();
         let remove = species
             .iter()
+            .filter(|x| Some(x.id) != best)
             .take(to_remove)
             .map(|x| x.id)
             .collect::<Vec<_>>();
@@ -56,13 +120,16 @@ impl Specie {
     }
 
     /// Update a species fitness
+    /// This locks the trainers agents, so it can't be called while they are locked
     pub fn update_fitness(&mut self) {
+        // Genomes that haven't been evaluated are left out
         let species = self.this_species();
-        let len = species.len();
+        let mut len = 0;
         let mut sum = 0.0;
 
-        for i in species {
-            sum += i.fitness.unwrap();
+        for i in species.iter().filter_map(|x| x.fitness) {
+            sum += i;
+            len += 1;
         }
 
         let mut fitness = sum / len as f32;
@@ -77,10 +144,21 @@ impl Specie {
         }
 
         self.fitness = Some(fitness);
+
+        // Stagnant species mutate their structure more to try and get out of a rut
+        let config = self.owner.trainer.config();
+        self.mutation_scale = if config.adaptive_mutation {
+            (1.0 + self.stagnant as f32 * config.adaptive_mutation_rate)
+                .min(config.adaptive_mutation_max)
+        } else {
+            1.0
+        };
     }
 
-    /// Gets the number of agents within the specie
-    /// This does rely on the `species_categorize` function being called before
+    /// Gets the number of agents within the specie by scanning the population, the `count` field is cheaper to read
+    /// This does rely on the `species_categorize` function being called before,
+    /// genomes added since (like fresh ones after a lethal generation) don't have a species yet so aren't counted.
+    /// It locks the trainers agents, so it can't be called while they are locked
     pub fn count(&self) -> usize {
         self.this_species().len()
     }
@@ -91,8 +169,54 @@ impl Specie {
             .agents
             .read()
             .iter()
-            .filter(|x| x.species.unwrap() == self.id)
+            .filter(|x| x.species == Some(self.id))
             .cloned()
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{mutated_population, trainer};
+
+    #[test]
+    fn all_lethal_generation() {
+        let trainer = trainer(|x| x.population_size = 10).populate();
+
+        // Every genome is removed, so fresh ones without a species are bred from
+        trainer.gen_checked(|_, _| None);
+        assert_eq!(trainer.agent_count(), 10);
+        // The fresh genomes haven't been categorized, so no species has any members yet
+        assert!(trainer.agents.read().iter().all(|x| x.species.is_none()));
+        assert!(!trainer.species.read().is_empty());
+        for i in trainer.species.read().iter() {
+            assert_eq!(i.count(), 0);
+        }
+        let mut species = trainer.species.write();
+        species.iter_mut().for_each(|x| x.update_fitness());
+        species.iter().for_each(|x| x.kill());
+        drop(species);
+
+        trainer.gen(|_, _| 1.0);
+        assert_eq!(trainer.agent_count(), 10);
+    }
+
+    #[test]
+    fn species_kill_keeps_a_member() {
+        let trainer = trainer(|x| {
+            x.population_kill_percent = 1.0;
+            x.compatibility_threshold = 1.0;
+            x.mutate_add_node = 0.5;
+        });
+        mutated_population(&trainer, 40);
+        trainer.species_categorize();
+        trainer.fitness(|i, _| i as f32);
+
+        let species = trainer.species.read();
+        assert!(species.len() > 1);
+        species.iter().for_each(|x| x.kill());
+        for x in species.iter() {
+            assert_eq!(x.count(), 1);
+        }
+    }
+}
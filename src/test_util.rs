@@ -0,0 +1,31 @@
+//! Fixtures shared by the unit tests
+
+use std::sync::Arc;
+
+use crate::{config::Config, genome::Genome, trainer::Trainer};
+
+// A trainer with 3 sensors and 2 outputs, with `config` changed by `f`
+pub fn trainer(f: impl FnOnce(&mut Config)) -> Arc<Trainer> {
+    let mut config = Config::default();
+    f(&mut config);
+    Arc::new(Trainer::new_with_config(3, 2, config))
+}
+
+// Genomes from the same trainer with a mix of shared and unshared structure,
+// each one is mutated a different number of times
+pub fn mutated_genomes(trainer: &Arc<Trainer>, count: usize) -> Vec<Genome> {
+    (0..count)
+        .map(|i| {
+            let mut genome = Genome::new(trainer.clone());
+            for _ in 0..i % 10 {
+                genome = genome.mutate();
+            }
+            genome
+        })
+        .collect()
+}
+
+// Replaces the population with `mutated_genomes`
+pub fn mutated_population(trainer: &Arc<Trainer>, count: usize) {
+    *trainer.agents.write() = mutated_genomes(trainer, count);
+}
@@ -1,90 +1,511 @@
 use std::borrow::Borrow;
 use std::mem;
 use std::sync::{
-    atomic::{AtomicUsize, Ordering},
+    atomic::{AtomicBool, AtomicUsize, Ordering},
     Arc,
 };
+#[cfg(not(target_arch = "wasm32"))]
 use std::time::Instant;
 
+use ahash::HashMap;
 use parking_lot::RwLock;
-use rand::seq::SliceRandom;
-use rand::{thread_rng, Rng};
+use rand::seq::{IteratorRandom, SliceRandom};
+use rand::{rngs::ThreadRng, thread_rng, Rng};
 
-use crate::innovation::Innovations;
-use crate::species::Specie;
-use crate::{config::Config, genome::Genome};
+use crate::innovation::{Innovations, SpecieCount};
+use crate::network::NodeLayout;
+use crate::species::{Specie, SpeciesStats};
+use crate::{
+    config::{Config, ConfigError, RecursionCheck, ReplacementStrategy, SelectionMethod},
+    genome::{self, Genome, GenomeError},
+};
+use crate::{misc::fitness_cmp, novelty, pareto};
 
 pub struct Trainer {
     // == INFO ==
-    pub inputs: usize,
-    pub outputs: usize,
+    pub layout: NodeLayout,
 
     // == GENOME ==
     pub agents: RwLock<Vec<Genome>>,
     /// Species ID, Case 0 Genome
     pub species: RwLock<Vec<Specie>>,
     pub innovator: Innovations,
+    /// Past behaviors novel enough to keep, used by [`Trainer::gen_novelty`]
+    pub novelty_archive: RwLock<Vec<Vec<f32>>>,
+    /// The genome used by [`Trainer::predict`]
+    pub best: RwLock<Option<Genome>>,
 
     // == SIMULATION ==
-    pub config: Config,
+    /// Read with [`Trainer::config`] and changed with [`Trainer::set_config`]
+    config: RwLock<Arc<Config>>,
     pub gen: AtomicUsize,
+    /// Set to stop [`Trainer::run`] after the current generation, can be cloned to another thread
+    pub should_stop: Arc<AtomicBool>,
+    /// Used in place of [`Genome::distance`] if set, see [`Trainer::with_distance`]
+    distance: Option<Box<dyn DistanceFn>>,
+    /// Used by [`Trainer::gen_par`] in place of the global rayon pool if set, see [`Trainer::with_thread_pool`]
+    #[cfg(feature = "rayon")]
+    thread_pool: Option<rayon::ThreadPool>,
+    /// The best genomes seen in any generation, best first, see [`Trainer::hall_of_fame`]
+    hall_of_fame: RwLock<Vec<Genome>>,
+    /// One entry per generation if `record_history` is set, see [`Trainer::history`]
+    history: RwLock<Vec<GenerationStats>>,
+    /// The member count of every species for each generation in `history`, see [`Trainer::species_history`]
+    species_history: RwLock<Vec<HashMap<SpecieCount, usize>>>,
+}
+
+/// A way of measuring how different two genomes are, used to put genomes into species.
+/// Any `Fn(&Genome, &Genome) -> f32` closure can be used.
+pub trait DistanceFn: Send + Sync {
+    fn distance(&self, a: &Genome, b: &Genome) -> f32;
+}
+
+impl<F: Fn(&Genome, &Genome) -> f32 + Send + Sync> DistanceFn for F {
+    fn distance(&self, a: &Genome, b: &Genome) -> f32 {
+        (self)(a, b)
+    }
+}
+
+/// The default [`DistanceFn`], which is [`Genome::distance`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GenomeDistance;
+
+impl DistanceFn for GenomeDistance {
+    fn distance(&self, a: &Genome, b: &Genome) -> f32 {
+        a.distance(b)
+    }
+}
+
+/// A summary of one generation, kept by the trainer if `record_history` is set
+#[derive(Debug, Clone, Copy)]
+pub struct GenerationStats {
+    pub generation: usize,
+    /// The highest raw fitness, `NaN` if every genome was lethal
+    pub max_fitness: f32,
+    /// The mean raw fitness of the genomes that weren't lethal, `NaN` if every genome was
+    pub mean_fitness: f32,
+    pub species_count: usize,
+    /// The mean [`Genome::node_count`] of the evaluated population
+    pub mean_nodes: f32,
+    /// The mean number of genes (including disabled ones) of the evaluated population
+    pub mean_edges: f32,
+}
+
+/// When to stop evolving in [`Trainer::run`]
+#[derive(Debug, Clone, Copy)]
+pub enum StopCondition {
+    /// Stop after this many generations
+    Generations(usize),
+    /// Stop once a genome reaches this raw fitness
+    FitnessThreshold(f32),
+    /// Stop after this many generations without the best fitness going up
+    Stagnation(usize),
 }
 
 impl Trainer {
     pub fn new(inputs: usize, outputs: usize) -> Self {
-        Self {
-            inputs,
-            outputs,
+        Self::new_with_config(inputs, outputs, Config::default())
+    }
+
+    /// Panics if the config is invalid, see [`Trainer::try_new_with_config`]
+    pub fn new_with_config(inputs: usize, outputs: usize, config: Config) -> Self {
+        Self::try_new_with_config(inputs, outputs, config)
+            .unwrap_or_else(|e| panic!("invalid config: {e}"))
+    }
+
+    /// Fails if the config is invalid, see [`Config::validate`]
+    pub fn try_new_with_config(
+        inputs: usize,
+        outputs: usize,
+        config: Config,
+    ) -> Result<Self, ConfigError> {
+        config.validate()?;
+        Ok(Self {
+            layout: NodeLayout::new(inputs, outputs),
             agents: RwLock::new(Vec::new()),
             species: RwLock::new(Vec::new()),
             innovator: Innovations::new(),
-            config: Config::default(),
+            novelty_archive: RwLock::new(Vec::new()),
+            best: RwLock::new(None),
+            config: RwLock::new(Arc::new(config)),
             gen: AtomicUsize::new(0),
+            should_stop: Arc::new(AtomicBool::new(false)),
+            distance: None,
+            #[cfg(feature = "rayon")]
+            thread_pool: None,
+            hall_of_fame: RwLock::new(Vec::new()),
+            history: RwLock::new(Vec::new()),
+            species_history: RwLock::new(Vec::new()),
+        })
+    }
+
+    /// Gets the current config.
+    /// This is a snapshot, so it won't see changes made by [`Trainer::set_config`] after it was taken
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().clone()
+    }
+
+    /// Replaces the config, for example to lower mutation rates as a run goes on.
+    /// Generations that have already started keep using the old config.
+    /// Fails without changing anything if the new config is invalid, see [`Config::validate`]
+    pub fn set_config(&self, config: Config) -> Result<(), ConfigError> {
+        config.validate()?;
+        *self.config.write() = Arc::new(config);
+        Ok(())
+    }
+
+    /// Uses a different distance function for speciation (and [`Trainer::diversity`]) in place of [`Genome::distance`].
+    /// `compatibility_threshold` has to be scaled to match it.
+    pub fn with_distance(mut self, distance: impl DistanceFn + 'static) -> Self {
+        self.distance = Some(Box::new(distance));
+        self
+    }
+
+    /// Gets the distance between two genomes, with the distance function set by [`Trainer::with_distance`] if there is one
+    pub fn distance(&self, a: &Genome, b: &Genome) -> f32 {
+        self.distance_with(&self.config(), a, b)
+    }
+
+    // Same as `distance`, but with an already taken config snapshot for loops over lots of genomes
+    fn distance_with(&self, config: &Config, a: &Genome, b: &Genome) -> f32 {
+        match &self.distance {
+            Some(distance) => distance.distance(a, b),
+            None => a.distance_with(b, config),
         }
     }
 
-    pub fn gen(&self, fit: impl Fn(usize, &Genome) -> f32) {
+    /// Makes [`Trainer::gen_par`] use its own pool of `threads` threads instead of the global rayon pool.
+    /// Any rayon work the fitness function does also runs on this pool, so it can't take more than `threads` threads.
+    #[cfg(feature = "rayon")]
+    pub fn with_thread_pool(mut self, threads: usize) -> Self {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .expect("failed to make thread pool");
+        self.thread_pool = Some(pool);
+        self
+    }
+
+    /// Marks a sensor as the bias, one that is always given 1.0
+    pub fn with_bias(mut self, sensor: usize) -> Self {
+        debug_assert!(sensor < self.layout.inputs);
+        self.layout.bias = Some(sensor);
+        self
+    }
+
+    /// Adds `count` constant sensors after the normal ones, see [`NodeLayout`].
+    /// `simulate` then takes `inputs + count` values, with the constants last.
+    pub fn with_constants(mut self, count: usize) -> Self {
+        self.layout.inputs += count;
+        self.layout.constants += count;
+        self
+    }
+
+    /// Evolves the population by one generation
+    /// Returns the best genome of the evaluated population along with its raw fitness
+    /// A summary of the generation is logged at the info level with the [`log`] crate
+    pub fn gen(&self, fit: impl Fn(usize, &Genome) -> f32) -> (Genome, f32) {
+        let (genome, fitness) = self.gen_checked(|i, e| Some((fit)(i, e)));
+        (genome, fitness.unwrap())
+    }
+
+    /// Evolves the population by one generation, with a fitness function that can mark genomes as lethal by returning `None`.
+    /// Lethal genomes are treated as the worst of the population and removed before culling, so they never breed.
+    /// Returns the best genome of the evaluated population along with its raw fitness,
+    /// or the first genome and `None` if every genome was lethal
+    pub fn gen_checked(
+        &self,
+        fit: impl Fn(usize, &Genome) -> Option<f32>,
+    ) -> (Genome, Option<f32>) {
+        self.evolve(|agents| {
+            agents
+                .iter()
+                .enumerate()
+                .map(|(i, e)| (fit)(i, e))
+                .collect()
+        })
+    }
+
+    /// Evolves the population by one generation, with a fitness function that returns a future.
+    /// Up to `async_max_in_flight` genomes are evaluated at once, on whatever runtime is polling this.
+    /// Returns the best genome of the evaluated population along with its raw fitness
+    #[cfg(feature = "async")]
+    pub async fn gen_async<F, Fut>(&self, fit: F) -> (Genome, f32)
+    where
+        F: Fn(usize, &Genome) -> Fut,
+        Fut: std::future::Future<Output = f32>,
+    {
+        use futures_util::{stream, StreamExt};
+
+        // Genomes keep their order through categorizing, so this lines up with the agents in `evolve`
+        let agents = self.agents.read().clone();
+        let fitness = stream::iter(agents.iter().enumerate())
+            .map(|(i, e)| (fit)(i, e))
+            .buffered(self.config().async_max_in_flight.max(1))
+            .map(Some)
+            .collect::<Vec<_>>()
+            .await;
+
+        let (genome, fitness) = self.evolve(|_| fitness);
+        (genome, fitness.unwrap())
+    }
+
+    /// Evolves the population by one generation, evaluating genomes in parallel with rayon.
+    /// Uses the pool from [`Trainer::with_thread_pool`] if there is one, otherwise the global pool.
+    /// The fitness function is called from many threads at once, so it has to be `Sync`,
+    /// meaning anything it changes has to be behind a lock or atomic.
+    /// Returns the best genome of the evaluated population along with its raw fitness
+    #[cfg(feature = "rayon")]
+    pub fn gen_par(&self, fit: impl Fn(usize, &Genome) -> f32 + Sync) -> (Genome, f32) {
+        use rayon::prelude::*;
+
+        let (genome, fitness) = self.evolve(|agents| {
+            let score = || {
+                agents
+                    .par_iter()
+                    .enumerate()
+                    .map(|(i, e)| Some((fit)(i, e)))
+                    .collect()
+            };
+            match &self.thread_pool {
+                Some(pool) => pool.install(score),
+                None => score(),
+            }
+        });
+        (genome, fitness.unwrap())
+    }
+
+    /// Evolves the population by one generation, with more than one fitness value (objective) per genome.
+    /// Genomes are ranked NSGA-II style, by their Pareto front then by how uncrowded they are within it.
+    /// Returns every genome on the first Pareto front along with its objectives.
+    pub fn gen_multi(&self, fit: impl Fn(usize, &Genome) -> Vec<f32>) -> Vec<(Genome, Vec<f32>)> {
+        let mut front = Vec::new();
+        self.evolve(|agents| {
+            let mut objectives = agents
+                .iter()
+                .enumerate()
+                .map(|(i, e)| (fit)(i, e))
+                .collect::<Vec<_>>();
+            let fronts = pareto::non_dominated_sort(&objectives);
+            let fitness = pareto::fitness(&objectives, &fronts);
+
+            front = fronts[0]
+                .iter()
+                .map(|&x| (agents[x].clone(), mem::take(&mut objectives[x])))
+                .collect();
+            fitness.into_iter().map(Some).collect()
+        });
+
+        front
+    }
+
+    /// Evolves the population by one generation with novelty search instead of fitness.
+    /// `behavior` describes what a genome does, and each genome is scored by the average distance from its
+    /// behavior to the `novelty_k` nearest behaviors in the rest of the population and the archive.
+    /// Behaviors with a novelty above `novelty_archive_threshold` are added to the archive.
+    /// Returns the most novel genome along with its novelty.
+    pub fn gen_novelty(&self, behavior: impl Fn(usize, &Genome) -> Vec<f32>) -> (Genome, f32) {
+        let (genome, novelty) = self.evolve(|agents| {
+            // One snapshot for the whole generation, so `set_config` can't change it partway through
+            let config = self.config();
+            let behaviors = agents
+                .iter()
+                .enumerate()
+                .map(|(i, e)| (behavior)(i, e))
+                .collect::<Vec<_>>();
+
+            let mut archive = self.novelty_archive.write();
+            let novelty = behaviors
+                .iter()
+                .enumerate()
+                .map(|(i, e)| {
+                    let others = behaviors
+                        .iter()
+                        .enumerate()
+                        .filter(|x| x.0 != i)
+                        .map(|x| x.1)
+                        .chain(archive.iter());
+                    novelty::novelty(e, others, config.novelty_k)
+                })
+                .collect::<Vec<_>>();
+
+            for (i, e) in behaviors.into_iter().enumerate() {
+                if novelty[i] > config.novelty_archive_threshold {
+                    archive.push(e);
+                }
+            }
+
+            novelty.into_iter().map(Some).collect()
+        });
+        (genome, novelty.unwrap())
+    }
+
+    // Runs one generation, `score` gives the raw fitness of every agent or `None` if it is lethal
+    // Returns the agent with the highest raw fitness along with it
+    fn evolve(&self, score: impl FnOnce(&[Genome]) -> Vec<Option<f32>>) -> (Genome, Option<f32>) {
+        #[cfg(not(target_arch = "wasm32"))]
         let start = Instant::now();
+        let config = self.config();
         self.species_categorize();
-        self.species
-            .write()
-            .iter_mut()
-            .for_each(|e| e.count = e.count());
 
-        // Update Fitnesses
-        let mut species = self.species.write();
-        self.agents
-            .write()
-            .iter_mut()
+        // No lock is held while calling something else that takes it,
+        // and when both are needed `species` is always locked before `agents`
+        let counts = self
+            .species
+            .read()
+            .iter()
+            .map(|x| (x.id, x.count))
+            .collect::<HashMap<_, _>>();
+
+        // Score a copy of the population, so the fitness function is free to use the trainer
+        let scored = self.agents.read().clone();
+        let fitness = score(&scored);
+        let best = fitness
+            .iter()
             .enumerate()
-            .for_each(|(i, e)| {
-                e.fitness = Some((fit)(i, e) / species[e.species.unwrap()].count as f32)
+            .filter_map(|(i, e)| e.map(|e| (i, e)))
+            .max_by(|a, b| {
+                config
+                    .tie_break
+                    .compare((&scored[a.0], a.1), (&scored[b.0], b.1))
+            });
+        let best = match best {
+            Some((i, e)) => (scored[i].clone(), Some(e)),
+            None => (scored[0].clone(), None),
+        };
+
+        self.update_hall_of_fame(&scored, &fitness, config.hall_of_fame_size);
+
+        // Worked out before reproduction so it describes the population that was scored
+        let history = config.record_history.then(|| {
+            let alive = fitness.iter().flatten().collect::<Vec<_>>();
+            let len = scored.len().max(1) as f32;
+            (
+                best.1.unwrap_or(f32::NAN),
+                alive.iter().copied().sum::<f32>() / alive.len() as f32,
+                scored.iter().map(|x| x.node_count()).sum::<usize>() as f32 / len,
+                scored.iter().map(|x| x.genes.len()).sum::<usize>() as f32 / len,
+            )
+        });
+        drop(scored);
+
+        // Update Fitnesses
+        // Lethal genomes are left without a fitness, which sorts them below everything else
+        for (e, fitness) in self.agents.write().iter_mut().zip(fitness) {
+            e.fitness = fitness.map(|fitness| {
+                if config.selection_uses_adjusted_fitness {
+                    fitness / counts[&e.species.unwrap()] as f32
+                } else {
+                    fitness
+                }
             });
+        }
+        *self.best.write() = Some(best.0.clone());
 
         let maxfit = self
             .agents
             .read()
             .iter()
-            .map(|x| x.fitness.unwrap())
+            .filter_map(|x| x.fitness)
             .fold(f32::MIN, |x, i| x.max(i));
 
-        species.iter_mut().for_each(|x| {
-            x.update_fitness();
-            x.kill();
-        });
+        let champions = self.species_champions(&self.species.read());
+        self.agents.write().retain(|x| x.fitness.is_some());
+        let species_count = {
+            let mut species = self.species.write();
+            species.iter_mut().for_each(|x| {
+                x.update_fitness();
+                if config.cull_per_species && x.age() >= config.species_grace_period {
+                    x.kill();
+                }
+            });
+            species.len()
+        };
+        if !config.cull_per_species {
+            self.kill();
+        }
 
-        self.repopulate();
+        // Champions (or survivors) are added after mutation so they make it through unchanged
+        let kept = match config.replacement {
+            ReplacementStrategy::Generational => {
+                self.fittest_survivors(champions, config.population_size, config.generation_gap)
+            }
+            ReplacementStrategy::SteadyState => self.agents.read().clone(),
+        };
+        self.repopulate(config.population_size.saturating_sub(kept.len()));
         self.mutate_population();
+        self.agents.write().extend(kept);
         self.gen.fetch_add(1, Ordering::AcqRel);
 
+        if let Some((max_fitness, mean_fitness, mean_nodes, mean_edges)) = history {
+            self.history.write().push(GenerationStats {
+                generation: self.generation(),
+                max_fitness,
+                mean_fitness,
+                species_count,
+                mean_nodes,
+                mean_edges,
+            });
+            self.species_history.write().push(counts);
+        }
+
         // Status message
-        println!(
-            "GEN: {:3} | MAXFIT: {:3.2}% | SPEC: {:2} | TIME: {}ms",
-            self.gen.load(Ordering::Acquire),
+        // There is no clock to time the generation with on wasm
+        #[cfg(not(target_arch = "wasm32"))]
+        let time = format!("{}ms", start.elapsed().as_millis());
+        #[cfg(target_arch = "wasm32")]
+        let time = "N/A";
+        log::info!(
+            "GEN: {:3} | MAXFIT: {:3.2}% | SPEC: {:2} | TIME: {}",
+            self.generation(),
             maxfit * 100.,
-            species.len(),
-            start.elapsed().as_millis()
+            species_count,
+            time
         );
+
+        best
+    }
+
+    /// Evolves the population until the stop condition is met or `should_stop` is set.
+    /// `should_stop` is checked between generations and cleared when it stops the run.
+    /// Returns the best genome seen during the run along with its raw fitness,
+    /// or `None` if no generations were run (`StopCondition::Generations(0)`)
+    pub fn run(
+        &self,
+        fit: impl Fn(usize, &Genome) -> f32,
+        stop: StopCondition,
+    ) -> Option<(Genome, f32)> {
+        let mut best: Option<(Genome, f32)> = None;
+        let mut gens = 0;
+        let mut stagnant = 0;
+
+        loop {
+            // Checked before the generation so a limit of 0 runs nothing
+            if matches!(stop, StopCondition::Generations(i) if gens >= i) {
+                return best;
+            }
+
+            let (genome, fitness) = self.gen(&fit);
+            gens += 1;
+
+            match best {
+                Some((_, best_fitness)) if fitness <= best_fitness => stagnant += 1,
+                _ => {
+                    best = Some((genome, fitness));
+                    stagnant = 0;
+                }
+            }
+
+            let done = match stop {
+                StopCondition::Generations(_) => false,
+                StopCondition::FitnessThreshold(i) => fitness >= i,
+                StopCondition::Stagnation(i) => stagnant >= i,
+            };
+
+            if done || self.should_stop.swap(false, Ordering::AcqRel) {
+                return best;
+            }
+        }
     }
 
     /// Create the innitial population
@@ -92,29 +513,154 @@ impl Trainer {
         let return_self = self.clone();
         let mut agents = self.agents.write();
 
-        for _ in agents.len()..self.config.population_size {
+        for _ in agents.len()..self.config().population_size {
             agents.push(Genome::new(self.clone()))
         }
+        drop(agents);
+
+        if self.config().auto_init_threshold {
+            self.init_threshold();
+        }
 
         return_self
     }
 
+    /// Replaces the population with mutated copies of `seed` (and one unchanged copy) instead of random genomes,
+    /// to start from a network that is known to be decent. Any genomes already in the population are thrown away.
+    /// The seed can come from another trainer with the same layout. Each gene takes this trainer's innovation for its connection,
+    /// or a new one if this trainer hasn't made that connection yet, so the seed's innovations can't clash with this trainer's.
+    /// Hidden node ids are kept as they are.
+    /// Fails if the seed's genes don't fit this trainer's layout, see [`Genome::from_genes`]
+    pub fn populate_from(self: Arc<Self>, seed: Genome) -> Result<Arc<Self>, GenomeError> {
+        let mut genes = seed.genes;
+        genome::check_layout(&genes, self.layout)?;
+        for i in &mut genes {
+            i.innovation = self.innovator.new_edge((i.node_in, i.node_out));
+        }
+        let mut genome = Genome::from_genes(self.clone(), genes)?;
+        genome.node_activations = seed.node_activations;
+
+        let population_size = self.config().population_size;
+        let mut agents = self.agents.write();
+        agents.clear();
+        agents.extend((0..population_size).map(|i| {
+            if i == 0 {
+                genome.clone()
+            } else {
+                genome.mutate()
+            }
+        }));
+        drop(agents);
+
+        if self.config().auto_init_threshold {
+            self.init_threshold();
+        }
+
+        Ok(self)
+    }
+
+    // Sets the compatibility threshold to the median distance between a sample of the population
+    fn init_threshold(&self) {
+        let config = self.config();
+        let agents = self.agents.read();
+        let sample = agents
+            .iter()
+            .choose_multiple(&mut thread_rng(), config.diversity_sample_size);
+
+        let mut distances = Vec::new();
+        for (i, a) in sample.iter().enumerate() {
+            for b in &sample[i + 1..] {
+                distances.push(self.distance_with(&config, a, b));
+            }
+        }
+        drop(agents);
+
+        distances.sort_by(|a, b| a.total_cmp(b));
+        let Some(&median) = distances.get(distances.len() / 2) else {
+            return;
+        };
+
+        // Genomes that are all the same have a distance of 0, which would put each in its own species
+        if median > 0.0 && median.is_finite() {
+            let mut config = (*config).clone();
+            config.compatibility_threshold = median;
+            *self.config.write() = Arc::new(config);
+        }
+    }
+
+    /// Throws away the current population and species, then populates again with fresh innovations
+    pub fn reset_population(self: Arc<Self>) -> Arc<Self> {
+        self.agents.write().clear();
+        self.species.write().clear();
+        self.novelty_archive.write().clear();
+        self.hall_of_fame.write().clear();
+        self.history.write().clear();
+        self.species_history.write().clear();
+        *self.best.write() = None;
+        self.innovator.reset();
+        self.gen.store(0, Ordering::Release);
+
+        self.populate()
+    }
+
+    /// Runs the best genome on these sensors.
+    /// The best genome is updated every generation, or can be picked from the current population with [`Trainer::refresh_best`].
+    /// Returns `None` if there isn't a best genome yet.
+    pub fn predict(&self, sensors: &[f32]) -> Option<Vec<f32>> {
+        if self.best.read().is_none() {
+            self.refresh_best();
+        }
+
+        self.best.read().as_ref().map(|x| x.simulate(sensors))
+    }
+
+    /// Sets the genome used by [`Trainer::predict`] to the one with the highest fitness in the current population.
+    /// Genomes that haven't been evaluated yet are ignored.
+    pub fn refresh_best(&self) {
+        let tie_break = self.config().tie_break;
+        let best = self
+            .agents
+            .read()
+            .iter()
+            .filter(|x| x.fitness.is_some())
+            .max_by(|a, b| tie_break.compare((a, a.fitness.unwrap()), (b, b.fitness.unwrap())))
+            .cloned();
+
+        if best.is_some() {
+            *self.best.write() = best;
+        }
+    }
+
     pub fn species_categorize(&self) {
         let mut rng = thread_rng();
-        let mut agents = self.agents.borrow().write();
         let mut species = self.species.borrow().write();
-        let working = agents.clone();
-        let mut working = working.iter().enumerate().collect::<Vec<_>>();
+        let mut agents = self.agents.borrow().write();
+        // One snapshot for the whole categorization, this is the hot loop
+        let config = self.config();
+        let deterministic = config.deterministic_speciation;
+        let mut working = (0..agents.len()).collect::<Vec<_>>();
         let mut used_species = Vec::new();
 
+        // Go through genomes in id order (newest last) and species in the order they were made
+        if deterministic {
+            working.sort_by_key(|x| std::cmp::Reverse(agents[*x].id));
+            species.sort_by_key(|x| x.id);
+        }
+
         'l: while !working.is_empty() {
-            // Get and remove random genome
-            let (agent_index, genome) = working.remove(rng.gen_range(0..working.len()));
+            // Get and remove the next genome
+            let agent_index = if deterministic {
+                working.pop().unwrap()
+            } else {
+                working.swap_remove(rng.gen_range(0..working.len()))
+            };
+            let genome = &agents[agent_index];
 
             // Compare it to every current species
+            // Genes are kept sorted, so each comparison is a single walk over both genomes
             for x in species.iter() {
-                let distance = x.owner.distance(genome);
-                if distance < self.config.compatibility_threshold {
+                let distance = self.distance_with(&config, &x.owner, genome);
+                if distance < config.compatibility_threshold {
                     agents[agent_index].species = Some(x.id);
                     used_species.push(x.id);
                     continue 'l;
@@ -131,96 +677,988 @@ impl Trainer {
         // Prune unused species
         species.retain(|x| used_species.contains(&x.id));
 
+        // Dissolve species that are too small
+        let sizes = species
+            .iter()
+            .map(|x| agents.iter().filter(|e| e.species == Some(x.id)).count())
+            .collect::<Vec<_>>();
+        let mut kept = (0..species.len())
+            .filter(|&x| sizes[x] >= config.min_species_size)
+            .collect::<Vec<_>>();
+        if kept.is_empty() {
+            kept.extend((0..species.len()).max_by_key(|&x| (sizes[x], std::cmp::Reverse(x))));
+        }
+
+        if kept.len() < species.len() {
+            let kept_ids = kept.iter().map(|&x| species[x].id).collect::<Vec<_>>();
+            for genome in agents.iter_mut() {
+                if genome.species.is_some_and(|x| kept_ids.contains(&x)) {
+                    continue;
+                }
+
+                let nearest = kept
+                    .iter()
+                    .map(|&x| {
+                        let distance = self.distance_with(&config, &species[x].owner, genome);
+                        (species[x].id, distance)
+                    })
+                    .min_by(|a, b| a.1.total_cmp(&b.1));
+                genome.species = nearest.map(|x| x.0);
+            }
+            species.retain(|x| kept_ids.contains(&x.id));
+        }
+
+        // Pick a random member (or the oldest if deterministic) of each species to represent it next generation,
+        // so the representative keeps up as the species changes
+        for x in species.iter_mut() {
+            let members = agents.iter().filter(|e| e.species == Some(x.id));
+            x.owner = if deterministic {
+                members.min_by_key(|e| e.id)
+            } else {
+                members.choose(&mut rng)
+            }
+            .unwrap()
+            .clone();
+        }
+
+        // Count the members of every species in one pass, so nothing else has to scan the population for it
+        let mut counts = HashMap::<SpecieCount, usize>::default();
+        for i in agents.iter().filter_map(|x| x.species) {
+            *counts.entry(i).or_default() += 1;
+        }
+        for x in species.iter_mut() {
+            x.count = counts.get(&x.id).copied().unwrap_or(0);
+        }
+
         debug_assert!(agents.iter().all(|x| x.species.is_some()));
+        debug_assert!(species
+            .iter()
+            .all(|x| x.count == agents.iter().filter(|e| e.species == Some(x.id)).count()));
     }
 
-    // TODO: Hashmap?
+    /// Scores a genome that doesn't have to be in the population, like a saved champion.
+    /// Takes the same fitness function as [`Trainer::gen`], which is given an index of 0.
+    pub fn evaluate_genome(&self, genome: &Genome, fit: impl Fn(usize, &Genome) -> f32) -> f32 {
+        (fit)(0, genome)
+    }
+
+    /// Works out the raw fitness of every genome, storing it in [`Genome::fitness`] as well as returning it
     pub fn fitness(&self, fitness: impl Fn(usize, &Genome) -> f32) -> Vec<f32> {
-        let agents = self.agents.borrow().read();
+        let mut agents = self.agents.borrow().write();
         agents
-            .iter()
+            .iter_mut()
             .enumerate()
-            .map(|(i, e)| (fitness)(i, e))
+            .map(|(i, e)| {
+                let fitness = (fitness)(i, e);
+                e.fitness = Some(fitness);
+                fitness
+            })
             .collect::<Vec<_>>()
     }
 
     /// Modifies a genome's fitness by the population of its spesies
     pub fn species_fitness(&self, fitness: &[f32]) -> Vec<f32> {
+        // Species counts come from `species_categorize`,
+        // genomes without a species (or whose species is gone) are counted in one pass
+        let mut counts = self
+            .species
+            .read()
+            .iter()
+            .map(|x| (Some(x.id), x.count))
+            .collect::<HashMap<_, _>>();
         let agents = self.agents.borrow().read();
-        let mut out = Vec::with_capacity(agents.len());
-
-        // nf = f / [count of genomes in the same spesies]
-        for (i, e) in fitness.iter().enumerate() {
-            let this_species = agents[i].species;
-            let count = agents.iter().filter(|x| x.species == this_species).count();
-            out.push(e / count as f32);
+        let mut uncounted = HashMap::<Option<SpecieCount>, usize>::default();
+        for i in agents.iter().filter(|x| !counts.contains_key(&x.species)) {
+            *uncounted.entry(i.species).or_default() += 1;
         }
+        counts.extend(uncounted);
 
-        out
+        // nf = f / [count of genomes in the same spesies]
+        fitness
+            .iter()
+            .zip(agents.iter())
+            .map(|(e, x)| e / counts[&x.species] as f32)
+            .collect()
     }
 
     pub fn mutate_population(&self) {
+        // Genomes without a species (or whose species is gone) use a scale of 1
+        let scales = self
+            .species
+            .read()
+            .iter()
+            .map(|x| (x.id, x.mutation_scale()))
+            .collect::<HashMap<_, _>>();
         let mut agents = self.agents.write();
-        for i in 0..10 {
-            agents.iter_mut().for_each(|x| *x = x.mutate());
+        for _ in 0..10 {
+            agents.iter_mut().for_each(|x| {
+                let scale = x.species.and_then(|x| scales.get(&x)).copied();
+                *x = x.mutate_scaled(scale.unwrap_or(1.0));
+            });
+        }
+    }
+
+    // Tops up `champions` with the fittest survivors of culling not already in it,
+    // until they make up `1 - generation_gap` of the population
+    fn fittest_survivors(
+        &self,
+        mut champions: Vec<Genome>,
+        population_size: usize,
+        generation_gap: f32,
+    ) -> Vec<Genome> {
+        let carry = ((1.0 - generation_gap) * population_size as f32).round() as usize;
+        if carry <= champions.len() {
+            return champions;
+        }
+
+        let agents = self.agents.read();
+        let mut survivors = agents
+            .iter()
+            .filter(|x| !champions.iter().any(|e| e.id == x.id))
+            .collect::<Vec<_>>();
+        let tie_break = self.config().tie_break;
+        survivors.sort_by(|a, b| {
+            tie_break.compare(
+                (b, b.fitness.unwrap_or(f32::NAN)),
+                (a, a.fitness.unwrap_or(f32::NAN)),
+            )
+        });
+        let extra = carry - champions.len();
+        champions.extend(survivors.into_iter().take(extra).cloned());
+        champions
+    }
+
+    // Adds any scored genome good enough to the hall of fame, keeping the best `size`.
+    // Champions are carried over unchanged, so a genome already in it (by id) isn't added again
+    fn update_hall_of_fame(&self, scored: &[Genome], fitness: &[Option<f32>], size: usize) {
+        let mut hall = self.hall_of_fame.write();
+        for (genome, fitness) in scored.iter().zip(fitness) {
+            let Some(fitness) = *fitness else {
+                continue;
+            };
+            let worst = hall.last().and_then(|x| x.fitness);
+            if hall.len() >= size && worst.is_none_or(|x| fitness_cmp(fitness, x).is_le())
+                || hall.iter().any(|x| x.id == genome.id)
+            {
+                continue;
+            }
+
+            // The fitness stored is the raw one, not adjusted for the species size
+            let mut genome = genome.clone();
+            genome.fitness = Some(fitness);
+            let index = hall
+                .partition_point(|x| fitness_cmp(x.fitness.unwrap_or(f32::NAN), fitness).is_ge());
+            hall.insert(index, genome);
+            hall.truncate(size);
         }
     }
 
-    pub fn repopulate(&self) {
+    // The id of the genome used by `predict`, culling always keeps it
+    pub(crate) fn best_id(&self) -> Option<usize> {
+        self.best.read().as_ref().map(|x| x.id)
+    }
+
+    /// Kills `population_kill_percent` of the whole population, worst first.
+    /// Unlike [`Specie::kill`] this can wipe out entire species.
+    /// Genomes in species younger than `species_grace_period` are never killed,
+    /// and neither is the best genome (the one with the highest raw fitness last generation).
+    pub fn kill(&self) {
+        let best = self.best_id();
+        let protected = self
+            .species
+            .read()
+            .iter()
+            .filter(|x| x.age() < self.config().species_grace_period)
+            .map(|x| x.id)
+            .collect::<Vec<_>>();
+
+        let mut agents = self.agents.write();
+        let to_remove = (agents.len() as f32 * self.config().population_kill_percent) as usize;
+        // Always leave two genomes to breed from
+        let to_remove = to_remove.min(agents.len().saturating_sub(2));
+
+        let tie_break = self.config().tie_break;
+        agents.sort_by(|a, b| {
+            tie_break.compare(
+                (a, a.fitness.unwrap_or(f32::NAN)),
+                (b, b.fitness.unwrap_or(f32::NAN)),
+            )
+        });
+        let mut removed = 0;
+        agents.retain(|x| {
+            let keep = removed >= to_remove
+                || Some(x.id) == best
+                || x.species.is_some_and(|x| protected.contains(&x));
+            if !keep {
+                removed += 1;
+            }
+            keep
+        });
+    }
+
+    /// Gets a copy of the best genome of every species with more than `champion_min_size` genomes
+    pub fn species_champions(&self, species: &[Specie]) -> Vec<Genome> {
+        let config = self.config();
+        let agents = self.agents.read();
+        species
+            .iter()
+            .filter(|x| x.count > config.champion_min_size)
+            .filter_map(|x| {
+                agents
+                    .iter()
+                    .filter(|e| e.species == Some(x.id))
+                    .filter_map(|e| e.fitness.map(|f| (e, f)))
+                    .max_by(|a, b| config.tie_break.compare(*a, *b))
+                    .map(|x| x.0)
+                    .cloned()
+            })
+            .collect()
+    }
+
+    /// Replaces the population with `count` children of the remaining genomes.
+    /// Each species gets a share of the children proportional to its summed fitness (see [`Trainer::offspring_quotas`])
+    /// and both parents of a child come from its species.
+    pub fn repopulate(&self, count: usize) {
         let mut rng = thread_rng();
         let mut agents = self.agents.write();
-        let mut new_agents = Vec::new();
-        debug_assert!(agents.len() > 1);
+        let mut new_agents = Vec::with_capacity(count);
 
-        while new_agents.len() < self.config.population_size {
-            // Find random genome
-            let i1 = rng.gen_range(0..agents.len());
-            let g1 = &agents[i1];
-            debug_assert!(g1.species.is_some());
+        // If culling left too few genomes to breed from, add fresh ones
+        // They don't have a species yet so they are bred together
+        let trainer = agents
+            .first()
+            .map(|x| x.trainer.clone())
+            .or_else(|| self.best.read().as_ref().map(|x| x.trainer.clone()));
+        if let Some(trainer) = trainer {
+            while agents.len() < self.config().min_population {
+                agents.push(Genome::new(trainer.clone()));
+            }
+        }
 
-            // Find another one within its species
-            let matching_agents = agents
-                .iter()
-                .enumerate()
-                .filter(|x| x.1.species == g1.species)
+        let fitness = agents
+            .iter()
+            .map(|x| x.fitness.unwrap_or(f32::NAN))
+            .collect::<Vec<_>>();
+        let selection = self.config().selection;
+        let crossover_trys = self.config().crossover_trys;
+        let check = self.config().recursion_check == RecursionCheck::Always;
+
+        for (species, quota) in Self::offspring_quotas(&agents, count) {
+            let members = (0..agents.len())
+                .filter(|&x| agents[x].species == species)
                 .collect::<Vec<_>>();
-            let (mut i2, mut g2) = matching_agents.choose(&mut rng).unwrap();
 
-            if matching_agents.len() <= 1 {
-                let index_agents = agents.iter().enumerate().collect::<Vec<_>>();
-                let rand = index_agents.choose(&mut rng).unwrap();
-                i2 = rand.0;
-                g2 = rand.1;
+            // If the species only has one genome left it is crossed with any other genome
+            let other_parent = |i1: usize, rng: &mut ThreadRng| {
+                if members.len() > 1 {
+                    *members.iter().filter(|&&x| x != i1).choose(rng).unwrap()
+                } else {
+                    (0..agents.len())
+                        .filter(|&x| x != i1)
+                        .choose(rng)
+                        .unwrap_or(i1)
+                }
+            };
+
+            // Pick two parents from the species for every child
+            let parents = match selection {
+                SelectionMethod::Uniform => (0..quota)
+                    .map(|_| {
+                        let i1 = *members.choose(&mut rng).unwrap();
+                        (i1, other_parent(i1, &mut rng))
+                    })
+                    .collect::<Vec<_>>(),
+                SelectionMethod::Sus => {
+                    let fitness = members.iter().map(|&x| fitness[x]).collect::<Vec<_>>();
+                    let first = sus(&fitness, quota, &mut rng);
+                    let mut second = sus(&fitness, quota, &mut rng);
+                    second.shuffle(&mut rng);
+                    first
+                        .into_iter()
+                        .zip(second)
+                        .map(|(a, b)| {
+                            let i1 = members[a];
+                            let i2 = if a == b {
+                                other_parent(i1, &mut rng)
+                            } else {
+                                members[b]
+                            };
+                            (i1, i2)
+                        })
+                        .collect()
+                }
+            };
+
+            for (i1, i2) in parents {
+                let (g1, g2) = (&agents[i1], &agents[i2]);
+
+                // Try to make a non recursive child
+                // If all `crossover_trys` attempts are recursive the first parent is copied instead,
+                // or a fresh genome is made if that is recursive too (its genes were changed by hand).
+                // Children are only checked with `RecursionCheck::Always`
+                let mut new = None;
+                for _ in 0..crossover_trys {
+                    let child = g1.crossover(g2, (fitness[i1], fitness[i2]));
+                    if !check || !child.is_recursive() {
+                        new = Some(child);
+                        break;
+                    }
+                }
+
+                let mut child = new.unwrap_or_else(|| {
+                    if check && g1.is_recursive() {
+                        Genome::new(g1.trainer.clone())
+                    } else {
+                        g1.clone()
+                    }
+                });
+                // Children keep their parents species until the next `species_categorize`,
+                // so `mutate_population` can use the species mutation scale
+                child.species = species;
+                new_agents.push(child);
             }
+        }
 
-            if i1 == i2 {
-                continue;
+        mem::swap(&mut *agents, &mut new_agents);
+        debug_assert_eq!(agents.len(), count);
+    }
+
+    /// Works out how many children each species gets, proportional to the sum of its genomes fitness.
+    /// Genomes without a species are treated as a species of their own.
+    /// The quotas always add up to `count`.
+    pub fn offspring_quotas(agents: &[Genome], count: usize) -> Vec<(Option<SpecieCount>, usize)> {
+        let mut sums = Vec::<(Option<SpecieCount>, f32)>::new();
+        for i in agents {
+            let species = i.species;
+            // NaN fitness is the worst, so it adds nothing
+            let fitness = i.fitness.filter(|x| !x.is_nan()).unwrap_or(0.0);
+            match sums.iter_mut().find(|x| x.0 == species) {
+                Some(x) => x.1 += fitness,
+                None => sums.push((species, fitness)),
+            }
+        }
+
+        if sums.is_empty() {
+            return Vec::new();
+        }
+
+        // Fitness can be negative, if any sum is below 0 everything is shifted up so the worst species has nothing.
+        // Sums that are all positive are left as they are, so the worst species still gets its share
+        let min = sums.iter().map(|x| x.1).fold(f32::MAX, f32::min).min(0.0);
+        sums.iter_mut().for_each(|x| x.1 -= min);
+        let total = sums.iter().map(|x| x.1).sum::<f32>();
+
+        let mut quotas = sums
+            .iter()
+            .map(|&(species, sum)| {
+                let share = if total > 0.0 {
+                    sum / total * count as f32
+                } else {
+                    count as f32 / sums.len() as f32
+                };
+                (species, share.floor() as usize, share.fract())
+            })
+            .collect::<Vec<_>>();
+
+        // Give whatever is left from rounding down to the species that lost the most to it
+        let given = quotas.iter().map(|x| x.1).sum::<usize>();
+        quotas.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal));
+        for i in 0..count.saturating_sub(given) {
+            let len = quotas.len();
+            quotas[i % len].1 += 1;
+        }
+
+        quotas.into_iter().map(|x| (x.0, x.1)).collect()
+    }
+}
+
+// Read only views of the training state, for logging and dashboards
+impl Trainer {
+    /// Gets the number of generations that have been run
+    pub fn generation(&self) -> usize {
+        self.gen.load(Ordering::Acquire)
+    }
+
+    /// Gets the mean distance between every pair of genomes in a random sample of `diversity_sample_size` genomes.
+    /// A falling diversity can be a sign the population is converging too early.
+    pub fn diversity(&self) -> f32 {
+        let config = self.config();
+        let agents = self.agents.read();
+        let sample = agents
+            .iter()
+            .choose_multiple(&mut thread_rng(), config.diversity_sample_size);
+
+        let mut sum = 0.0;
+        let mut pairs = 0;
+        for (i, a) in sample.iter().enumerate() {
+            for b in &sample[i + 1..] {
+                sum += self.distance_with(&config, a, b);
+                pairs += 1;
+            }
+        }
+
+        if pairs == 0 {
+            return 0.0;
+        }
+
+        sum / pairs as f32
+    }
+
+    /// Calls `f` on every genome in the current population
+    pub fn for_each_agent(&self, f: impl FnMut(&Genome)) {
+        self.agents.read().iter().for_each(f);
+    }
+
+    /// Gets the mean [`Genome::parameter_count`] of the current population, or 0 if it is empty
+    pub fn mean_parameter_count(&self) -> f32 {
+        let agents = self.agents.read();
+        if agents.is_empty() {
+            return 0.0;
+        }
+
+        agents.iter().map(|x| x.parameter_count()).sum::<usize>() as f32 / agents.len() as f32
+    }
+
+    /// Gets the number of genomes in the current population
+    pub fn agent_count(&self) -> usize {
+        self.agents.read().len()
+    }
+
+    /// Gets the number of species as of the last generation
+    pub fn species_count(&self) -> usize {
+        self.species.read().len()
+    }
+
+    /// Gets the stats of every species as of the last generation
+    pub fn species_snapshot(&self) -> Vec<SpeciesStats> {
+        self.species.read().iter().map(|x| x.stats()).collect()
+    }
+
+    /// Gets the best genomes (by raw fitness, best first) from every generation so far, up to `hall_of_fame_size`.
+    /// Unlike [`Trainer::best`] these are never lost when the population is replaced.
+    /// The genomes `fitness` is their raw fitness.
+    /// This is a copy (at most `hall_of_fame_size` genomes), so holding on to it never blocks a generation
+    pub fn hall_of_fame(&self) -> Vec<Genome> {
+        self.hall_of_fame.read().clone()
+    }
+
+    /// Gets the stats of every generation so far, this is empty unless `record_history` is set
+    pub fn history(&self) -> Vec<GenerationStats> {
+        self.history.read().clone()
+    }
+
+    /// Gets the member count of every species (by id) for each generation, in the same order as [`Trainer::history`].
+    /// Species ids are never reused, so this can be used to plot species over time.
+    /// This is empty unless `record_history` is set
+    pub fn species_history(&self) -> Vec<HashMap<SpecieCount, usize>> {
+        self.species_history.read().clone()
+    }
+
+    /// Gets [`Trainer::history`] as CSV with a header row, for plotting
+    pub fn history_csv(&self) -> String {
+        let mut out = String::from(
+            "generation,max_fitness,mean_fitness,species_count,mean_nodes,mean_edges\n",
+        );
+        for i in self.history.read().iter() {
+            out.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                i.generation,
+                i.max_fitness,
+                i.mean_fitness,
+                i.species_count,
+                i.mean_nodes,
+                i.mean_edges
+            ));
+        }
+        out
+    }
+}
+
+// Stochastic universal sampling, picks `count` indices of `fitness` with one random start and evenly spaced pointers.
+// Fitness is shifted so the worst is 0 and NaN counts as 0, if everything is the same each index is as likely.
+fn sus(fitness: &[f32], count: usize, rng: &mut impl Rng) -> Vec<usize> {
+    if count == 0 || fitness.is_empty() {
+        return Vec::new();
+    }
+
+    let min = fitness
+        .iter()
+        .copied()
+        .filter(|x| !x.is_nan())
+        .fold(f32::MAX, f32::min);
+    let mut weights = fitness
+        .iter()
+        .map(|x| if x.is_nan() { 0.0 } else { x - min })
+        .collect::<Vec<_>>();
+    if weights.iter().sum::<f32>() <= 0.0 {
+        weights.iter_mut().for_each(|x| *x = 1.0);
+    }
+
+    let total = weights.iter().sum::<f32>();
+    let step = total / count as f32;
+    let start = rng.gen_range(0.0..step.max(f32::MIN_POSITIVE));
+
+    let mut out = Vec::with_capacity(count);
+    let (mut index, mut cumulative) = (0, weights[0]);
+    for i in 0..count {
+        let pointer = start + step * i as f32;
+        while pointer >= cumulative && index + 1 < weights.len() {
+            index += 1;
+            cumulative += weights[index];
+        }
+        out.push(index);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    use parking_lot::Mutex;
+
+    use crate::{
+        config::Config,
+        genome::{Gene, Genome},
+        test_util::{mutated_population, trainer},
+    };
+
+    use super::{StopCondition, Trainer};
+
+    // The compatibility distance as it was before the allocation-free walk
+    fn old_distance(a: &Genome, b: &Genome, config: &Config) -> f32 {
+        let a_genes = a
+            .genes
+            .iter()
+            .map(|x| (x.innovation, x))
+            .collect::<BTreeMap<_, _>>();
+        let b_genes = b
+            .genes
+            .iter()
+            .map(|x| (x.innovation, x))
+            .collect::<BTreeMap<_, _>>();
+        let matching = a_genes
+            .iter()
+            .filter_map(|(i, x)| b_genes.get(i).map(|y| (*x, *y)))
+            .collect::<Vec<_>>();
+        let nonmatching = a_genes
+            .iter()
+            .filter(|x| !b_genes.contains_key(x.0))
+            .chain(b_genes.iter().filter(|x| !a_genes.contains_key(x.0)))
+            .map(|x| *x.0);
+
+        let min_max_innovation = a
+            .genes
+            .last()
+            .zip(b.genes.last())
+            .map(|(a, b)| a.innovation.min(b.innovation));
+        let (e, d) = nonmatching.fold((0, 0), |(e, d), x| {
+            if min_max_innovation.is_none_or(|max| x > max) {
+                (e + 1, d)
+            } else {
+                (e, d + 1)
             }
+        });
+
+        let mut n = a.genes.len().max(b.genes.len());
+        if n < 20 {
+            n = 1;
+        }
+        let mut w = matching
+            .iter()
+            .map(|(a, b)| (a.weight - b.weight).abs())
+            .sum::<f32>()
+            / matching.len() as f32;
+        if w.is_nan() {
+            w = 0.0;
+        }
+
+        let n = n as f32;
+        (config.excess_comp * e as f32 / n)
+            + (config.disjoint_comp * d as f32 / n)
+            + config.weight_comp * w
+    }
 
-            let mut tries = self.config.mutate_add_edge_tries;
-            let mut new = None;
-            while tries > 0 {
-                // new = Some(g1.crossover(g2));
-                new = Some({
-                    let mut mango = g1.clone();
-                    mango.id = self.innovator.new_genome();
-                    mango 
+    // Each species as the set of its genome ids
+    fn partition(groups: impl Iterator<Item = (usize, usize)>) -> BTreeSet<BTreeSet<usize>> {
+        let mut species = BTreeMap::<usize, BTreeSet<usize>>::new();
+        for (specie, genome) in groups {
+            species.entry(specie).or_default().insert(genome);
+        }
+        species.into_values().collect()
+    }
+
+    #[test]
+    fn gen_with_concurrent_readers() {
+        let trainer = trainer(|x| {
+            x.population_size = 40;
+            x.cull_per_species = true;
+        })
+        .populate();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let readers = (0..4)
+            .map(|_| {
+                let (trainer, stop) = (trainer.clone(), stop.clone());
+                thread::spawn(move || {
+                    while !stop.load(Ordering::Acquire) {
+                        trainer.species_snapshot();
+                        trainer.diversity();
+                        trainer.hall_of_fame();
+                        trainer.mean_parameter_count();
+                        trainer.for_each_agent(|x| {
+                            x.simulate(&[1.0, 0.0, 1.0]);
+                        });
+                        trainer.refresh_best();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // The fitness function uses the trainer too, which mustn't deadlock
+        let (send, receive) = mpsc::channel();
+        let runner = trainer.clone();
+        thread::spawn(move || {
+            for _ in 0..50 {
+                runner.gen(|_, x| {
+                    runner.agent_count();
+                    x.simulate(&[1.0, 0.5, -0.5])[0].abs()
                 });
-                if new.as_ref().unwrap().is_recursive() {
-                    tries -= 1;
-                    continue;
+            }
+            send.send(()).unwrap();
+        });
+
+        let done = receive.recv_timeout(Duration::from_secs(120));
+        stop.store(true, Ordering::Release);
+        assert!(done.is_ok(), "generations didn't finish, likely a deadlock");
+        readers.into_iter().for_each(|x| x.join().unwrap());
+        assert_eq!(trainer.generation(), 50);
+    }
+
+    #[test]
+    fn repopulate_respects_crossover_trys() {
+        // With no tries every child is a copy of its first parent
+        let trainer = trainer(|x| {
+            x.crossover_trys = 0;
+            x.mutate_add_node = 0.3;
+        });
+        mutated_population(&trainer, 50);
+        trainer.species_categorize();
+        trainer.fitness(|i, _| i as f32);
+        let parents = trainer.agents.read().clone();
+
+        trainer.repopulate(50);
+        let genes = |x: &Genome| {
+            x.genes
+                .iter()
+                .map(|x| (x.innovation, x.weight.to_bits(), x.enabled))
+                .collect::<Vec<_>>()
+        };
+        for child in trainer.agents.read().iter() {
+            assert!(parents.iter().any(|x| genes(x) == genes(child)));
+        }
+    }
+
+    #[test]
+    fn representatives_are_current_members() {
+        for deterministic in [false, true] {
+            let trainer = trainer(|x| {
+                x.deterministic_speciation = deterministic;
+                x.population_size = 50;
+                x.mutate_add_node = 0.3;
+            });
+            let trainer = trainer.populate();
+            for _ in 0..10 {
+                trainer.gen(|_, x| x.genes.len() as f32);
+                trainer.species_categorize();
+
+                let agents = trainer.agents.read();
+                for x in trainer.species.read().iter() {
+                    let member = agents.iter().find(|e| e.id == x.owner.id);
+                    assert_eq!(member.and_then(|e| e.species), Some(x.id));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn champions_survive_unmutated() {
+        let trainer = trainer(|x| {
+            x.population_size = 60;
+            x.mutate_weight = 1.0;
+            x.mutate_add_node = 0.3;
+        });
+        let trainer = trainer.populate();
+        let genes = |x: &Genome| {
+            x.genes
+                .iter()
+                .map(|x| (x.innovation, x.weight.to_bits(), x.enabled))
+                .collect::<Vec<_>>()
+        };
+
+        let mut checked = 0;
+        for _ in 0..5 {
+            // The fitness function sees every genome with its species for this generation
+            let scored = Mutex::new(Vec::new());
+            trainer.gen(|i, x| {
+                scored.lock().push((i, x.clone()));
+                i as f32 + 1.0
+            });
+            let scored = scored.into_inner();
+
+            let mut best = BTreeMap::<usize, (usize, &Genome)>::new();
+            let mut sizes = BTreeMap::<usize, usize>::new();
+            for (i, genome) in &scored {
+                let species = genome.species.unwrap();
+                *sizes.entry(species).or_default() += 1;
+                let entry = best.entry(species).or_insert((*i, genome));
+                if *i > entry.0 {
+                    *entry = (*i, genome);
                 }
+            }
 
-                break;
+            let agents = trainer.agents.read();
+            let min_size = trainer.config().champion_min_size;
+            for (species, (_, champion)) in best {
+                if sizes[&species] <= min_size {
+                    continue;
+                }
+                let kept = agents.iter().find(|x| x.id == champion.id);
+                assert_eq!(kept.map(genes), Some(genes(champion)));
+                checked += 1;
             }
+        }
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn young_species_survive_culling() {
+        let trainer = trainer(|x| {
+            x.cull_per_species = false;
+            x.species_grace_period = 3;
+            x.mutate_add_node = 0.3;
+        });
+        mutated_population(&trainer, 40);
+        trainer.species_categorize();
+        trainer.fitness(|i, _| i as f32);
 
-            new_agents.push(match new {
-                Some(i) => i,
-                None => continue,
+        // Every species was just made, so nothing can be killed
+        trainer.kill();
+        assert_eq!(trainer.agent_count(), 40);
+
+        // Once the grace period is over they are culled as normal
+        trainer.gen.store(3, Ordering::Release);
+        trainer.kill();
+        assert_eq!(trainer.agent_count(), 4);
+    }
+
+    #[test]
+    fn dissolved_species_are_reassigned() {
+        let trainer = trainer(|x| {
+            x.min_species_size = 4;
+            x.compatibility_threshold = 1.0;
+            x.mutate_add_node = 0.5;
+        });
+        mutated_population(&trainer, 40);
+
+        // Without dissolving, some species are too small
+        let config = trainer.config();
+        trainer
+            .set_config(Config {
+                min_species_size: 1,
+                ..config.as_ref().clone()
+            })
+            .unwrap();
+        trainer.species_categorize();
+        assert!(trainer.species.read().iter().any(|x| x.count < 4));
+
+        trainer.set_config(config.as_ref().clone()).unwrap();
+        trainer.species_categorize();
+        let species = trainer.species.read();
+        let agents = trainer.agents.read();
+        assert!(!species.is_empty());
+        for x in species.iter() {
+            assert!(x.count >= 4);
+        }
+        for x in agents.iter() {
+            assert!(species.iter().any(|e| Some(e.id) == x.species));
+        }
+        assert_eq!(species.iter().map(|x| x.count).sum::<usize>(), 40);
+    }
+
+    #[test]
+    fn best_genome_is_never_culled() {
+        for cull_per_species in [false, true] {
+            let trainer = trainer(|x| {
+                x.population_kill_percent = 1.0;
+                x.mutate_add_node = 0.3;
             });
+            mutated_population(&trainer, 40);
+            trainer.species_categorize();
+            trainer.fitness(|i, _| i as f32);
+
+            // The worst genome is made the best, so it would be the first to go
+            let worst = trainer.agents.read()[0].clone();
+            let id = worst.id;
+            *trainer.best.write() = Some(worst);
+            if cull_per_species {
+                trainer.species.read().iter().for_each(|x| x.kill());
+            } else {
+                trainer.kill();
+            }
+            assert!(trainer.agents.read().iter().any(|x| x.id == id));
         }
+    }
 
-        mem::swap(&mut *agents, &mut new_agents);
-        debug_assert_eq!(agents.len(), self.config.population_size);
+    #[test]
+    fn repopulate_rejects_recursive_children() {
+        // Every parent has a loop edited in by hand, so every crossover is rejected
+        let trainer = trainer(|x| x.mutate_add_node = 0.3);
+        mutated_population(&trainer, 20);
+        for genome in trainer.agents.write().iter_mut() {
+            genome.genes.clear();
+            for (i, (a, b)) in [(0, 3), (0, 5), (5, 6), (6, 5), (6, 3)]
+                .into_iter()
+                .enumerate()
+            {
+                genome.genes.push(Gene {
+                    node_in: a,
+                    node_out: b,
+                    weight: 0.5,
+                    enabled: true,
+                    innovation: 100 + i,
+                    generation_added: 0,
+                });
+            }
+            genome.invalidate_topology();
+            assert!(genome.is_recursive());
+        }
+        trainer.species_categorize();
+        trainer.fitness(|i, _| i as f32);
+
+        trainer.repopulate(30);
+        let agents = trainer.agents.read();
+        assert_eq!(agents.len(), 30);
+        assert!(agents.iter().all(|x| !x.is_recursive()));
+    }
+
+    #[test]
+    fn run_zero_generations() {
+        let trainer = trainer(|x| x.population_size = 20).populate();
+        assert!(trainer
+            .run(|_, _| 1.0, StopCondition::Generations(0))
+            .is_none());
+        assert_eq!(trainer.generation(), 0);
+
+        assert!(trainer
+            .run(|_, _| 1.0, StopCondition::Generations(2))
+            .is_some());
+        assert_eq!(trainer.generation(), 2);
+    }
+
+    #[test]
+    fn populate_from_another_trainer() {
+        // Both trainers hand out the same innovations for different connections
+        let other = trainer(|x| x.mutate_add_node = 0.5);
+        mutated_population(&other, 30);
+        let seed = other.agents.read().last().unwrap().clone();
+        let trainer = trainer(|x| x.mutate_add_node = 0.5);
+        mutated_population(&trainer, 30);
+
+        let trainer = trainer.populate_from(seed.clone()).unwrap();
+        let agents = trainer.agents.read();
+        assert_eq!(agents.len(), trainer.config().population_size);
+        let pairs = |x: &Genome| {
+            x.genes
+                .iter()
+                .map(|x| (x.node_in, x.node_out))
+                .collect::<BTreeSet<_>>()
+        };
+        assert_eq!(pairs(&agents[0]), pairs(&seed));
+
+        // Every connection has one innovation across the whole population
+        let mut innovations = BTreeMap::new();
+        for x in agents.iter().flat_map(|x| &x.genes) {
+            let pair = (x.node_in, x.node_out);
+            assert_eq!(*innovations.entry(x.innovation).or_insert(pair), pair);
+            assert_eq!(trainer.innovator.new_edge(pair), x.innovation);
+        }
+    }
+
+    #[test]
+    fn species_fitness_divides_by_count() {
+        let trainer = trainer(|x| x.mutate_add_node = 0.5);
+        mutated_population(&trainer, 30);
+        trainer.species_categorize();
+        // A fresh genome without a species is counted on its own
+        let fresh = Genome::new(trainer.clone());
+        trainer.agents.write().push(fresh);
+
+        let fitness = (0..31).map(|x| x as f32).collect::<Vec<_>>();
+        let adjusted = trainer.species_fitness(&fitness);
+        let agents = trainer.agents.read();
+        for (i, x) in agents.iter().enumerate() {
+            let count = agents.iter().filter(|e| e.species == x.species).count();
+            assert_eq!(adjusted[i], fitness[i] / count as f32);
+        }
+    }
+
+    #[test]
+    fn offspring_quotas_with_negative_sums() {
+        let trainer = trainer(|_| {});
+        let genomes = [(0, -1.0), (1, -0.5), (1, -1.5), (2, -6.0)].map(|(species, fitness)| {
+            let mut genome = Genome::new(trainer.clone());
+            genome.species = Some(species);
+            genome.fitness = Some(fitness);
+            genome
+        });
+
+        // The sums are -1, -2 and -6, shifted up by 6 to 5, 4 and 0
+        let mut quotas = Trainer::offspring_quotas(&genomes, 9);
+        quotas.sort();
+        assert_eq!(quotas, [(Some(0), 5), (Some(1), 4), (Some(2), 0)]);
+    }
+
+    #[test]
+    fn speciation_matches_old_distance() {
+        let trainer = trainer(|x| {
+            x.deterministic_speciation = true;
+            x.compatibility_threshold = 0.6;
+            x.mutate_add_node = 0.3;
+        });
+        mutated_population(&trainer, 200);
+        let config = trainer.config();
+
+        // The categorization loop before the speedup, in deterministic order
+        let mut agents = trainer.agents.read().clone();
+        agents.sort_by_key(|x| x.id);
+        let mut owners = Vec::<&Genome>::new();
+        let mut old = Vec::new();
+        for genome in &agents {
+            let specie = owners
+                .iter()
+                .position(|x| old_distance(x, genome, &config) < config.compatibility_threshold)
+                .unwrap_or_else(|| {
+                    owners.push(genome);
+                    owners.len() - 1
+                });
+            old.push((specie, genome.id));
+        }
+
+        trainer.species_categorize();
+        let new = trainer
+            .agents
+            .read()
+            .iter()
+            .map(|x| (x.species.unwrap(), x.id))
+            .collect::<Vec<_>>();
+
+        assert!(owners.len() > 1);
+        assert_eq!(partition(old.into_iter()), partition(new.into_iter()));
     }
 }